@@ -0,0 +1,116 @@
+use backend::LockBackend;
+use cancel::CancelToken;
+use error::LockError;
+#[cfg(feature = "tokio")]
+use error::Operation;
+use file_options::FileOptions;
+use retry::RetryPolicy;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use wait::Wait;
+use FileLock;
+
+pub(crate) type Acquire = Box<dyn FnOnce() -> Result<FileLock, LockError> + Send>;
+
+/// Taking out a lock on a path for a future, on whatever thread it hands the work to
+///
+/// Nothing happens until [`Acquisition::start`]. Dropping the acquisition
+/// gives up waiting, and a lock taken out in the meantime is released again
+/// once the result is dropped, wherever it ends up.
+pub(crate) struct Acquisition {
+    acquire: Option<Acquire>,
+    cancel: CancelToken,
+    path: PathBuf,
+    started: Instant,
+}
+
+impl Acquisition {
+    /// Lock `path`, waiting for it to become available if `is_blocking`
+    pub(crate) fn new<B: LockBackend + 'static>(
+        path: &Path,
+        is_blocking: bool,
+        options: FileOptions,
+        backend: B,
+    ) -> Self {
+        let cancel = CancelToken::new();
+        // F_SETLKW can't be woken up, so waiting polls and watches for us being dropped
+        let wait = match is_blocking {
+            true => Wait::cancellable(&cancel),
+            false => Wait::NonBlocking,
+        };
+
+        Self::with_wait(path, wait, options, backend, cancel)
+    }
+
+    /// Lock `path`, waiting at most `timeout` for it to become available
+    pub(crate) fn timeout<B: LockBackend + 'static>(
+        path: &Path,
+        timeout: Duration,
+        options: FileOptions,
+        backend: B,
+    ) -> Self {
+        let cancel = CancelToken::new();
+        let wait = Wait::Poll {
+            policy: RetryPolicy::default().deadline(timeout),
+            cancel: Some(cancel.clone()),
+        };
+
+        Self::with_wait(path, wait, options, backend, cancel)
+    }
+
+    fn with_wait<B: LockBackend + 'static>(
+        path: &Path,
+        wait: Wait,
+        options: FileOptions,
+        backend: B,
+        cancel: CancelToken,
+    ) -> Self {
+        let path = path.to_path_buf();
+        let task_path = path.clone();
+
+        Acquisition {
+            acquire: Some(Box::new(move || {
+                FileLock::acquire(task_path, wait, options, 0, 0, backend)
+            })),
+            cancel,
+            path,
+            started: Instant::now(),
+        }
+    }
+
+    /// The work to hand to another thread, the first time only
+    pub(crate) fn start(&mut self) -> Option<Acquire> {
+        let acquire = self.acquire.take()?;
+        self.started = Instant::now();
+
+        Some(acquire)
+    }
+
+    /// What to report when the thread doing the work went away without a result
+    #[cfg(feature = "tokio")]
+    pub(crate) fn cancelled(&self) -> LockError {
+        LockError::Cancelled {
+            operation: Operation::Lock,
+            path: Some(self.path.clone()),
+            waited: self.started.elapsed(),
+        }
+    }
+}
+
+impl Drop for Acquisition {
+    fn drop(&mut self) {
+        // stop the thread doing the work from waiting any longer
+        self.cancel.cancel();
+    }
+}
+
+impl fmt::Debug for Acquisition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Acquisition")
+            .field("path", &self.path)
+            .field("started", &self.acquire.is_none())
+            .field("cancelled", &self.cancel.is_cancelled())
+            .finish()
+    }
+}
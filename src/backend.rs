@@ -0,0 +1,413 @@
+//! The locking mechanisms a [`FileLock`](../struct.FileLock.html) can be taken out with.
+//!
+//! All built-in backends are thin wrappers around the C shim. Implement
+//! [`LockBackend`] to plug in any other mechanism.
+
+use libc::{c_int, off_t, pid_t};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::Error;
+use std::os::unix::io::RawFd;
+
+extern "C" {
+    fn c_lock(fd: i32, is_blocking: i32, is_writeable: i32, start: off_t, len: off_t) -> c_int;
+    fn c_unlock(fd: i32, start: off_t, len: off_t) -> c_int;
+    fn c_ofd_lock(fd: i32, is_blocking: i32, is_writeable: i32, start: off_t, len: off_t) -> c_int;
+    fn c_ofd_unlock(fd: i32, start: off_t, len: off_t) -> c_int;
+    fn c_getlk(
+        fd: i32,
+        is_writeable: i32,
+        start: off_t,
+        len: off_t,
+        is_locked: *mut c_int,
+        is_locked_writeable: *mut c_int,
+        locked_start: *mut off_t,
+        locked_len: *mut off_t,
+        pid: *mut pid_t,
+    ) -> c_int;
+    fn c_ofd_getlk(
+        fd: i32,
+        is_writeable: i32,
+        start: off_t,
+        len: off_t,
+        is_locked: *mut c_int,
+        is_locked_writeable: *mut c_int,
+        locked_start: *mut off_t,
+        locked_len: *mut off_t,
+        pid: *mut pid_t,
+    ) -> c_int;
+    fn c_flock(fd: i32, is_blocking: i32, is_writeable: i32) -> c_int;
+    fn c_funlock(fd: i32) -> c_int;
+    fn c_lockf(fd: i32, is_blocking: i32, start: off_t, len: off_t) -> c_int;
+    fn c_unlockf(fd: i32, start: off_t, len: off_t) -> c_int;
+}
+
+/// Whether a lock may be held by several readers or by a single writer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LockKind {
+    /// A read lock, which other shared locks may overlap
+    Shared,
+    /// A write lock, which no other lock may overlap
+    Exclusive,
+}
+
+/// A lock held on (a byte range of) a file, as reported by [`LockBackend::query`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LockInfo {
+    /// Whether the lock is shared or exclusive
+    pub kind: LockKind,
+    /// The offset of the first locked byte
+    pub start: u64,
+    /// The number of locked bytes, where `0` means up to the end of the file
+    pub len: u64,
+    /// The process holding the lock, if known
+    ///
+    /// Open file description locks aren't owned by a process, so this is
+    /// always `None` for them.
+    pub pid: Option<u32>,
+}
+
+impl fmt::Display for LockInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            LockKind::Shared => "shared",
+            LockKind::Exclusive => "exclusive",
+        };
+
+        match self.len {
+            0 => write!(f, "{} lock from byte {} on", kind, self.start)?,
+            _ => write!(
+                f,
+                "{} lock on bytes {}..{}",
+                kind,
+                self.start,
+                self.start.saturating_add(self.len)
+            )?,
+        }
+
+        match self.pid {
+            Some(pid) => write!(f, " held by pid {}", pid),
+            None => write!(f, " held by an unknown owner"),
+        }
+    }
+}
+
+/// A mechanism to lock and unlock (a byte range of) an open file
+///
+/// A `len` of `0` stands for everything from `start` up to the end of the
+/// file, however large it grows. Backends which can't lock ranges should fail
+/// with `EINVAL` for anything but `start == 0 && len == 0`.
+///
+/// # Examples
+///
+///```
+///extern crate file_lock;
+///
+///use file_lock::backend::Posix;
+///use file_lock::{FileLock, FileOptions, LockBackend, LockKind};
+///use std::io::Error;
+///use std::os::unix::io::RawFd;
+///
+///#[derive(Debug)]
+///struct Logged(Posix);
+///
+///impl LockBackend for Logged {
+///    fn lock(&self, fd: RawFd, kind: LockKind, is_blocking: bool, start: u64, len: u64) -> Result<(), Error> {
+///        println!("locking fd {} ({:?})", fd, kind);
+///        self.0.lock(fd, kind, is_blocking, start, len)
+///    }
+///
+///    fn unlock(&self, fd: RawFd, start: u64, len: u64) -> Result<(), Error> {
+///        println!("unlocking fd {}", fd);
+///        self.0.unlock(fd, start, len)
+///    }
+///}
+///
+///fn main() {
+///    let options = FileOptions::new().write(true).create(true);
+///
+///    match FileLock::lock_with("myfile.txt", true, options, Logged(Posix)) {
+///        Ok(_) => println!("Got the lock"),
+///        Err(err) => panic!("Error getting write lock: {}", err),
+///    };
+///}
+///```
+pub trait LockBackend: fmt::Debug + Send + Sync {
+    /// Lock the given range of `fd`, waiting for it to become available if `is_blocking`
+    fn lock(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        is_blocking: bool,
+        start: u64,
+        len: u64,
+    ) -> Result<(), Error>;
+
+    /// Unlock the given range of `fd`
+    fn unlock(&self, fd: RawFd, start: u64, len: u64) -> Result<(), Error>;
+
+    /// Find a lock which would prevent us from locking the given range of `fd` as `kind`
+    ///
+    /// Returns `None` if the range could be locked right now. Backends which
+    /// can't tell fail with `EOPNOTSUPP`, which is what the default does.
+    fn query(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        start: u64,
+        len: u64,
+    ) -> Result<Option<LockInfo>, Error> {
+        let _ = (fd, kind, start, len);
+        Err(Error::from_raw_os_error(libc::EOPNOTSUPP))
+    }
+
+    /// Whether shared locks need the file to be open for reading, and
+    /// exclusive ones for writing
+    ///
+    /// If so, which is the default as `fcntl()` fails with `EBADF` otherwise,
+    /// the access mode is checked up front for a clearer error.
+    fn needs_access_mode(&self) -> bool {
+        true
+    }
+
+    /// Whether locks belong to the whole process rather than to `fd`
+    ///
+    /// Such locks never conflict with each other within a process, so
+    /// [`FileLock`](../struct.FileLock.html) keeps track of them itself to
+    /// make threads exclude each other too. The default is `false`.
+    fn is_process_owned(&self) -> bool {
+        false
+    }
+}
+
+/// Classic POSIX record locks via `F_SETLK`/`F_SETLKW`
+///
+/// See [`LockMode::Posix`](../enum.LockMode.html#variant.Posix).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Posix;
+
+/// Linux open file description locks via `F_OFD_SETLK`/`F_OFD_SETLKW`
+///
+/// See [`LockMode::Ofd`](../enum.LockMode.html#variant.Ofd).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Ofd;
+
+/// BSD style whole-file locks via `flock(2)`
+///
+/// See [`LockMode::Flock`](../enum.LockMode.html#variant.Flock).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flock;
+
+/// Exclusive-only POSIX record locks, for code written against `lockf(3)`
+///
+/// See [`LockMode::Lockf`](../enum.LockMode.html#variant.Lockf).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Lockf;
+
+impl LockBackend for Posix {
+    fn lock(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        is_blocking: bool,
+        start: u64,
+        len: u64,
+    ) -> Result<(), Error> {
+        let (start, len) = c_range(start, len)?;
+        let is_writeable = kind == LockKind::Exclusive;
+
+        errno_result(unsafe { c_lock(fd, is_blocking as i32, is_writeable as i32, start, len) })
+    }
+
+    fn unlock(&self, fd: RawFd, start: u64, len: u64) -> Result<(), Error> {
+        let (start, len) = c_range(start, len)?;
+
+        errno_result(unsafe { c_unlock(fd, start, len) })
+    }
+
+    fn query(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        start: u64,
+        len: u64,
+    ) -> Result<Option<LockInfo>, Error> {
+        get_lock(c_getlk, fd, kind, start, len)
+    }
+
+    fn is_process_owned(&self) -> bool {
+        true
+    }
+}
+
+impl LockBackend for Ofd {
+    fn lock(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        is_blocking: bool,
+        start: u64,
+        len: u64,
+    ) -> Result<(), Error> {
+        let (start, len) = c_range(start, len)?;
+        let is_writeable = kind == LockKind::Exclusive;
+
+        errno_result(unsafe { c_ofd_lock(fd, is_blocking as i32, is_writeable as i32, start, len) })
+    }
+
+    fn unlock(&self, fd: RawFd, start: u64, len: u64) -> Result<(), Error> {
+        let (start, len) = c_range(start, len)?;
+
+        errno_result(unsafe { c_ofd_unlock(fd, start, len) })
+    }
+
+    fn query(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        start: u64,
+        len: u64,
+    ) -> Result<Option<LockInfo>, Error> {
+        get_lock(c_ofd_getlk, fd, kind, start, len)
+    }
+}
+
+impl LockBackend for Flock {
+    fn lock(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        is_blocking: bool,
+        start: u64,
+        len: u64,
+    ) -> Result<(), Error> {
+        if start != 0 || len != 0 {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        let is_writeable = kind == LockKind::Exclusive;
+
+        errno_result(unsafe { c_flock(fd, is_blocking as i32, is_writeable as i32) })
+    }
+
+    fn unlock(&self, fd: RawFd, start: u64, len: u64) -> Result<(), Error> {
+        if start != 0 || len != 0 {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+
+        errno_result(unsafe { c_funlock(fd) })
+    }
+
+    fn needs_access_mode(&self) -> bool {
+        false
+    }
+}
+
+impl LockBackend for Lockf {
+    fn lock(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        is_blocking: bool,
+        start: u64,
+        len: u64,
+    ) -> Result<(), Error> {
+        if kind == LockKind::Shared {
+            return Err(Error::from_raw_os_error(libc::EINVAL));
+        }
+        let (start, len) = c_range(start, len)?;
+
+        errno_result(unsafe { c_lockf(fd, is_blocking as i32, start, len) })
+    }
+
+    fn unlock(&self, fd: RawFd, start: u64, len: u64) -> Result<(), Error> {
+        let (start, len) = c_range(start, len)?;
+
+        errno_result(unsafe { c_unlockf(fd, start, len) })
+    }
+
+    fn query(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        start: u64,
+        len: u64,
+    ) -> Result<Option<LockInfo>, Error> {
+        // these are plain fcntl() locks
+        Posix.query(fd, kind, start, len)
+    }
+
+    fn is_process_owned(&self) -> bool {
+        true
+    }
+}
+
+fn c_range(start: u64, len: u64) -> Result<(off_t, off_t), Error> {
+    match (off_t::try_from(start), off_t::try_from(len)) {
+        (Ok(start), Ok(len)) => Ok((start, len)),
+        _ => Err(Error::from_raw_os_error(libc::EINVAL)),
+    }
+}
+
+type GetLk = unsafe extern "C" fn(
+    i32,
+    i32,
+    off_t,
+    off_t,
+    *mut c_int,
+    *mut c_int,
+    *mut off_t,
+    *mut off_t,
+    *mut pid_t,
+) -> c_int;
+
+fn get_lock(
+    getlk: GetLk,
+    fd: RawFd,
+    kind: LockKind,
+    start: u64,
+    len: u64,
+) -> Result<Option<LockInfo>, Error> {
+    let (start, len) = c_range(start, len)?;
+    let is_writeable = kind == LockKind::Exclusive;
+
+    let mut is_locked = 0;
+    let mut is_locked_writeable = 0;
+    let mut locked_start = 0;
+    let mut locked_len = 0;
+    let mut pid = 0;
+
+    errno_result(unsafe {
+        getlk(
+            fd,
+            is_writeable as i32,
+            start,
+            len,
+            &mut is_locked,
+            &mut is_locked_writeable,
+            &mut locked_start,
+            &mut locked_len,
+            &mut pid,
+        )
+    })?;
+
+    if is_locked == 0 {
+        return Ok(None);
+    }
+
+    Ok(Some(LockInfo {
+        kind: match is_locked_writeable {
+            0 => LockKind::Shared,
+            _ => LockKind::Exclusive,
+        },
+        start: u64::try_from(locked_start).unwrap_or(0),
+        len: u64::try_from(locked_len).unwrap_or(0),
+        pid: u32::try_from(pid).ok().filter(|pid| *pid > 0),
+    }))
+}
+
+fn errno_result(errno: c_int) -> Result<(), Error> {
+    match errno {
+        0 => Ok(()),
+        _ => Err(Error::from_raw_os_error(errno)),
+    }
+}
@@ -0,0 +1,114 @@
+use backend::{LockBackend, LockKind};
+use error::LockError;
+use lock_mode::LockMode;
+use lock_state::LockState;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd};
+use std::ptr;
+use std::time::Duration;
+use wait::Wait;
+
+/// A lock on a file which is owned by someone else
+///
+/// Unlike [`FileLock`](struct.FileLock.html) this only borrows the file,
+/// which stays usable by its owner while it is locked. The lock is released
+/// on drop, the file is left open.
+///
+/// # Examples
+///
+///```
+///extern crate file_lock;
+///
+///use file_lock::{BorrowedFileLock, LockKind};
+///use std::fs::OpenOptions;
+///use std::io::prelude::*;
+///
+///fn main() {
+///    let mut file = OpenOptions::new().write(true).create(true).open("myfile.txt").unwrap();
+///
+///    {
+///        let _lock = match BorrowedFileLock::lock(&file, true, LockKind::Exclusive) {
+///            Ok(lock) => lock,
+///            Err(err) => panic!("Error getting write lock: {}", err),
+///        };
+///
+///        (&file).write_all(b"Hello, World!").is_ok();
+///    }
+///
+///    // The file is still ours once the lock is gone
+///    file.write_all(b"Goodbye!").is_ok();
+///}
+///```
+#[derive(Debug)]
+pub struct BorrowedFileLock<'a> {
+    fd: BorrowedFd<'a>,
+    state: LockState,
+}
+
+impl<'a> BorrowedFileLock<'a> {
+    /// Try to lock a file we don't own
+    ///
+    /// # Parameters
+    ///
+    /// `file` is anything holding an open file descriptor, which must outlive the lock
+    ///
+    /// `is_blocking` is a flag to indicate if we should block if it's already locked
+    ///
+    /// `kind` is whether to take out a shared or an exclusive lock, which
+    /// must match the access mode `file` has been opened with
+    pub fn lock<F: AsFd>(
+        file: &'a F,
+        is_blocking: bool,
+        kind: LockKind,
+    ) -> Result<BorrowedFileLock<'a>, LockError> {
+        Self::lock_with(file, is_blocking, kind, LockMode::Posix)
+    }
+
+    /// Try to lock a file we don't own using the given [`LockBackend`]
+    ///
+    /// See [`BorrowedFileLock::lock`] for details.
+    pub fn lock_with<F: AsFd, B: LockBackend + 'static>(
+        file: &'a F,
+        is_blocking: bool,
+        kind: LockKind,
+        backend: B,
+    ) -> Result<BorrowedFileLock<'a>, LockError> {
+        let fd = file.as_fd();
+        let wait = Wait::from_blocking(is_blocking);
+        let state = LockState::acquire(fd.as_raw_fd(), kind, wait, 0, 0, Box::new(backend), None)?;
+
+        Ok(BorrowedFileLock { fd, state })
+    }
+
+    /// The [`LockBackend`] this lock was taken out with
+    pub fn backend(&self) -> &dyn LockBackend {
+        &*self.state.backend
+    }
+
+    /// How long we had to wait for the lock to become available
+    pub fn waited(&self) -> Duration {
+        self.state.waited
+    }
+
+    /// Whether we currently hold a shared or an exclusive lock
+    pub fn kind(&self) -> LockKind {
+        self.state.kind
+    }
+
+    /// Unlock the file, which stays open
+    ///
+    /// *Note:* This method is optional as the file lock will be unlocked automatically when dropped
+    pub fn unlock(self) -> Result<(), LockError> {
+        let lock = ManuallyDrop::new(self);
+        // `lock` is never dropped, so this is the only owner of the state
+        let state = unsafe { ptr::read(&lock.state) };
+
+        state.release(lock.fd.as_raw_fd())
+    }
+}
+
+impl<'a> Drop for BorrowedFileLock<'a> {
+    fn drop(&mut self) {
+        let _ = self.state.release(self.fd.as_raw_fd()).is_ok();
+    }
+}
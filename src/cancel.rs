@@ -0,0 +1,67 @@
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// A handle to call off waiting for a lock from another thread
+///
+/// Clones share their state, so one can be handed to the thread waiting
+/// for the lock and another one kept to cancel it. Once cancelled, a token
+/// stays cancelled.
+///
+/// # Examples
+///
+///```
+///extern crate file_lock;
+///
+///use file_lock::{CancelToken, FileLock, FileOptions, LockError};
+///use std::thread;
+///
+///fn main() {
+///    let token = CancelToken::new();
+///    let worker_token = token.clone();
+///
+///    let worker = thread::spawn(move || {
+///        let options = FileOptions::new().write(true).create(true);
+///
+///        match FileLock::lock_cancellable("myfile.txt", &worker_token, options) {
+///            Ok(_) => println!("Got the lock"),
+///            Err(LockError::Cancelled { .. }) => println!("Shutting down"),
+///            Err(err) => panic!("Error getting write lock: {}", err),
+///        }
+///    });
+///
+///    token.cancel();
+///    worker.join().unwrap();
+///}
+///```
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl CancelToken {
+    /// Create a token which hasn't been cancelled yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call off waiting for the lock, waking up whoever waits right away
+    pub fn cancel(&self) {
+        let (ref cancelled, ref condvar) = *self.inner;
+
+        *cancelled.lock().unwrap_or_else(|err| err.into_inner()) = true;
+        condvar.notify_all();
+    }
+
+    /// Whether [`CancelToken::cancel`] has been called
+    pub fn is_cancelled(&self) -> bool {
+        *self.inner.0.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Sleep for `duration`, or until cancelled if that happens first
+    pub(crate) fn sleep(&self, duration: Duration) {
+        let (ref cancelled, ref condvar) = *self.inner;
+
+        let guard = cancelled.lock().unwrap_or_else(|err| err.into_inner());
+        let _ = condvar.wait_timeout_while(guard, duration, |cancelled| !*cancelled);
+    }
+}
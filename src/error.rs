@@ -0,0 +1,349 @@
+use backend::{LockInfo, LockKind};
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// What we were doing when a [`LockError`] occurred
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Opening the file to lock
+    Open,
+    /// Taking out a lock
+    Lock,
+    /// Releasing a lock
+    Unlock,
+    /// Turning a shared lock into an exclusive one
+    Upgrade,
+    /// Turning an exclusive lock into a shared one
+    Downgrade,
+    /// Looking for conflicting locks
+    Query,
+    /// Writing new contents and putting them in place of the locked file
+    Replace,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Operation::Open => "open",
+            Operation::Lock => "lock",
+            Operation::Unlock => "unlock",
+            Operation::Upgrade => "upgrade the lock on",
+            Operation::Downgrade => "downgrade the lock on",
+            Operation::Query => "query the locks on",
+            Operation::Replace => "replace",
+        })
+    }
+}
+
+/// Why locking, unlocking or querying a file failed
+///
+/// Converts into an [`io::Error`] for compatibility, which carries the
+/// original OS error code wherever there is one.
+///
+/// # Examples
+///
+///```
+///extern crate file_lock;
+///
+///use file_lock::{FileLock, FileOptions, LockError};
+///
+///fn main() {
+///    let options = FileOptions::new().write(true).create(true);
+///
+///    match FileLock::lock("myfile.txt", false, options) {
+///        Ok(_) => println!("Got the lock"),
+///        Err(LockError::WouldBlock { holder: Some(holder), .. }) => println!("Busy: {}", holder),
+///        Err(err) => panic!("Error getting write lock: {}", err),
+///    }
+///}
+///```
+#[derive(Debug)]
+pub enum LockError {
+    /// The file to lock could not be opened
+    Open {
+        /// The file we tried to open
+        path: PathBuf,
+        /// What went wrong
+        source: io::Error,
+    },
+    /// The lock is held elsewhere, and we were told not to wait for it
+    WouldBlock {
+        /// What we were doing
+        operation: Operation,
+        /// The locked file, if known
+        path: Option<PathBuf>,
+        /// The lock standing in our way, if the backend could tell
+        ///
+        /// This is looked up after the fact, so it is `None` as well when
+        /// the lock has been released in the meantime.
+        holder: Option<LockInfo>,
+    },
+    /// The file isn't open for the access the lock needs: reading for a
+    /// shared lock, writing for an exclusive one
+    AccessMode {
+        /// What we were doing
+        operation: Operation,
+        /// The file we tried to lock, if known
+        path: Option<PathBuf>,
+        /// The kind of lock we tried to take out
+        kind: LockKind,
+    },
+    /// Waiting for the lock would have deadlocked (`EDEADLK`)
+    Deadlock {
+        /// What we were doing
+        operation: Operation,
+        /// The locked file, if known
+        path: Option<PathBuf>,
+    },
+    /// The lock did not become available in time
+    TimedOut {
+        /// What we were doing
+        operation: Operation,
+        /// The locked file, if known
+        path: Option<PathBuf>,
+        /// How long we actually waited before giving up
+        waited: Duration,
+    },
+    /// A [`RetryPolicy`](struct.RetryPolicy.html) ran out of attempts
+    RetriesExhausted {
+        /// What we were doing
+        operation: Operation,
+        /// The locked file, if known
+        path: Option<PathBuf>,
+        /// How many times we tried
+        attempts: u32,
+        /// How long we tried for
+        waited: Duration,
+    },
+    /// Waiting for the lock was called off through a [`CancelToken`](struct.CancelToken.html)
+    Cancelled {
+        /// What we were doing
+        operation: Operation,
+        /// The locked file, if known
+        path: Option<PathBuf>,
+        /// How long we waited before being cancelled
+        waited: Duration,
+    },
+    /// Waiting for the lock was interrupted by a signal (`EINTR`)
+    ///
+    /// Blocking waits carry on after a signal unless told otherwise by
+    /// [`LockOptions::interruptible`](struct.LockOptions.html#method.interruptible).
+    Interrupted {
+        /// What we were doing
+        operation: Operation,
+        /// The file we tried to lock, if known
+        path: Option<PathBuf>,
+    },
+    /// The system ran out of locks (`ENOLCK`)
+    NoLocksAvailable {
+        /// What we were doing
+        operation: Operation,
+        /// The file we tried to lock, if known
+        path: Option<PathBuf>,
+    },
+    /// The file system or backend does not support this kind of locking
+    Unsupported {
+        /// What we were doing
+        operation: Operation,
+        /// The file we tried to lock, if known
+        path: Option<PathBuf>,
+        /// The error reported by the backend
+        source: io::Error,
+    },
+    /// Changing the kind of lock failed, and so did getting the old one
+    /// back, so no lock is held anymore
+    ///
+    /// Only happens with backends which can't convert locks in place, such
+    /// as [`LockMode::Flock`](enum.LockMode.html#variant.Flock).
+    Lost {
+        /// What we were doing
+        operation: Operation,
+        /// The file we held a lock on, if known
+        path: Option<PathBuf>,
+        /// Why the old lock could not be taken out again
+        source: io::Error,
+    },
+    /// Any other error reported by the backend
+    Io {
+        /// What we were doing
+        operation: Operation,
+        /// The file we tried to lock, if known
+        path: Option<PathBuf>,
+        /// The error reported by the backend
+        source: io::Error,
+    },
+}
+
+impl LockError {
+    /// Classify an error reported by a backend
+    pub(crate) fn from_io(operation: Operation, source: io::Error) -> Self {
+        let path = None;
+
+        match source.raw_os_error() {
+            Some(libc::EDEADLK) => LockError::Deadlock { operation, path },
+            Some(libc::EINTR) => LockError::Interrupted { operation, path },
+            Some(libc::ENOLCK) => LockError::NoLocksAvailable { operation, path },
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => LockError::Unsupported {
+                operation,
+                path,
+                source,
+            },
+            _ => LockError::Io {
+                operation,
+                path,
+                source,
+            },
+        }
+    }
+
+    /// Attach the path of the file the error is about
+    pub(crate) fn with_path(mut self, file: Option<&Path>) -> Self {
+        match self {
+            LockError::Open { .. } => {}
+            LockError::WouldBlock { ref mut path, .. }
+            | LockError::AccessMode { ref mut path, .. }
+            | LockError::Deadlock { ref mut path, .. }
+            | LockError::TimedOut { ref mut path, .. }
+            | LockError::RetriesExhausted { ref mut path, .. }
+            | LockError::Cancelled { ref mut path, .. }
+            | LockError::Interrupted { ref mut path, .. }
+            | LockError::NoLocksAvailable { ref mut path, .. }
+            | LockError::Unsupported { ref mut path, .. }
+            | LockError::Lost { ref mut path, .. }
+            | LockError::Io { ref mut path, .. } => *path = file.map(Path::to_path_buf),
+        }
+        self
+    }
+
+    /// What we were doing when the error occurred
+    pub fn operation(&self) -> Operation {
+        match *self {
+            LockError::Open { .. } => Operation::Open,
+            LockError::WouldBlock { operation, .. }
+            | LockError::AccessMode { operation, .. }
+            | LockError::Deadlock { operation, .. }
+            | LockError::TimedOut { operation, .. }
+            | LockError::RetriesExhausted { operation, .. }
+            | LockError::Cancelled { operation, .. }
+            | LockError::Interrupted { operation, .. }
+            | LockError::NoLocksAvailable { operation, .. }
+            | LockError::Unsupported { operation, .. }
+            | LockError::Lost { operation, .. }
+            | LockError::Io { operation, .. } => operation,
+        }
+    }
+
+    /// The file the error is about, if known
+    pub fn path(&self) -> Option<&Path> {
+        match *self {
+            LockError::Open { ref path, .. } => Some(path),
+            LockError::WouldBlock { ref path, .. }
+            | LockError::AccessMode { ref path, .. }
+            | LockError::Deadlock { ref path, .. }
+            | LockError::TimedOut { ref path, .. }
+            | LockError::RetriesExhausted { ref path, .. }
+            | LockError::Cancelled { ref path, .. }
+            | LockError::Interrupted { ref path, .. }
+            | LockError::NoLocksAvailable { ref path, .. }
+            | LockError::Unsupported { ref path, .. }
+            | LockError::Lost { ref path, .. }
+            | LockError::Io { ref path, .. } => path.as_deref(),
+        }
+    }
+
+    /// The OS error code behind this error, if there is one
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match *self {
+            LockError::Open { ref source, .. }
+            | LockError::Unsupported { ref source, .. }
+            | LockError::Io { ref source, .. } => source.raw_os_error(),
+            LockError::WouldBlock { .. } => Some(libc::EWOULDBLOCK),
+            LockError::Deadlock { .. } => Some(libc::EDEADLK),
+            LockError::Interrupted { .. } => Some(libc::EINTR),
+            LockError::NoLocksAvailable { .. } => Some(libc::ENOLCK),
+            LockError::AccessMode { .. }
+            | LockError::TimedOut { .. }
+            | LockError::RetriesExhausted { .. }
+            | LockError::Cancelled { .. }
+            | LockError::Lost { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for LockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to {}", self.operation())?;
+
+        if let Some(path) = self.path() {
+            write!(f, " {}", path.display())?;
+        }
+
+        match *self {
+            LockError::Open { ref source, .. } => write!(f, ": {}", source),
+            LockError::WouldBlock {
+                holder: Some(LockInfo { pid: Some(pid), .. }),
+                ..
+            } => {
+                write!(f, ": locked by pid {}", pid)
+            }
+            LockError::WouldBlock {
+                holder: Some(_), ..
+            } => write!(f, ": locked by another open file"),
+            LockError::WouldBlock { holder: None, .. } => write!(f, ": locked elsewhere"),
+            LockError::AccessMode {
+                kind: LockKind::Shared,
+                ..
+            } => write!(f, ": a shared lock needs the file to be open for reading"),
+            LockError::AccessMode {
+                kind: LockKind::Exclusive,
+                ..
+            } => write!(
+                f,
+                ": an exclusive lock needs the file to be open for writing"
+            ),
+            LockError::Deadlock { .. } => write!(f, ": waiting would deadlock"),
+            LockError::TimedOut { waited, .. } => write!(f, ": timed out after {:?}", waited),
+            LockError::RetriesExhausted {
+                attempts, waited, ..
+            } => write!(f, ": gave up after {} attempts in {:?}", attempts, waited),
+            LockError::Cancelled { waited, .. } => write!(f, ": cancelled after {:?}", waited),
+            LockError::Interrupted { .. } => write!(f, ": interrupted"),
+            LockError::NoLocksAvailable { .. } => write!(f, ": no locks available"),
+            LockError::Unsupported { ref source, .. } => write!(f, ": not supported ({})", source),
+            LockError::Lost { ref source, .. } => write!(f, ": the lock was lost ({})", source),
+            LockError::Io { ref source, .. } => write!(f, ": {}", source),
+        }
+    }
+}
+
+impl error::Error for LockError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            LockError::Open { ref source, .. }
+            | LockError::Unsupported { ref source, .. }
+            | LockError::Lost { ref source, .. }
+            | LockError::Io { ref source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<LockError> for io::Error {
+    fn from(err: LockError) -> io::Error {
+        match err {
+            LockError::Open { source, .. }
+            | LockError::Unsupported { source, .. }
+            | LockError::Io { source, .. } => source,
+            LockError::AccessMode { .. } => io::Error::new(io::ErrorKind::InvalidInput, err),
+            LockError::TimedOut { .. } => io::Error::new(io::ErrorKind::TimedOut, err),
+            LockError::RetriesExhausted { .. } => io::Error::new(io::ErrorKind::WouldBlock, err),
+            _ => match err.raw_os_error() {
+                Some(errno) => io::Error::from_raw_os_error(errno),
+                None => io::Error::other(err),
+            },
+        }
+    }
+}
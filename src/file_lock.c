@@ -1,40 +1,145 @@
+#define _GNU_SOURCE
+
 #include <errno.h>
 #include <fcntl.h>
+#include <string.h>
+#include <sys/file.h>
+#include <sys/types.h>
+#include <unistd.h>
 
-int c_lock(int fd, int is_blocking, int is_writable) {
+static int set_lock(int fd, int cmd, short type, off_t start, off_t len) {
   if (fd < 0) {
     return EBADF;
   }
 
   struct flock fl;
 
-  fl.l_type   = is_writable ? F_WRLCK : F_RDLCK;
+  memset(&fl, 0, sizeof(fl));
+
+  fl.l_type   = type;
   fl.l_whence = SEEK_SET;
-  fl.l_start  = 0;
-  fl.l_len    = 0;
+  fl.l_start  = start;
+  fl.l_len    = len;
 
-  if (fcntl(fd, is_blocking ? F_SETLKW : F_SETLK, &fl) == -1) {
+  if (fcntl(fd, cmd, &fl) == -1) {
     return errno;
   }
 
   return 0;
 }
 
-int c_unlock(int fd) {
+static int get_lock(int fd, int cmd, int is_writable, off_t start, off_t len,
+                    int *is_locked, int *is_locked_writable, off_t *locked_start, off_t *locked_len, pid_t *pid) {
+  if (fd < 0) {
+    return EBADF;
+  }
+
   struct flock fl;
 
+  memset(&fl, 0, sizeof(fl));
+
+  fl.l_type   = is_writable ? F_WRLCK : F_RDLCK;
+  fl.l_whence = SEEK_SET;
+  fl.l_start  = start;
+  fl.l_len    = len;
+
+  if (fcntl(fd, cmd, &fl) == -1) {
+    return errno;
+  }
+
+  *is_locked          = fl.l_type != F_UNLCK;
+  *is_locked_writable = fl.l_type == F_WRLCK;
+  *locked_start       = fl.l_start;
+  *locked_len         = fl.l_len;
+  *pid                = fl.l_pid;
+
+  return 0;
+}
+
+int c_lock(int fd, int is_blocking, int is_writable, off_t start, off_t len) {
+  return set_lock(fd, is_blocking ? F_SETLKW : F_SETLK, is_writable ? F_WRLCK : F_RDLCK, start, len);
+}
+
+int c_unlock(int fd, off_t start, off_t len) {
+  return set_lock(fd, F_SETLK, F_UNLCK, start, len);
+}
+
+int c_getlk(int fd, int is_writable, off_t start, off_t len,
+            int *is_locked, int *is_locked_writable, off_t *locked_start, off_t *locked_len, pid_t *pid) {
+  return get_lock(fd, F_GETLK, is_writable, start, len, is_locked, is_locked_writable, locked_start, locked_len, pid);
+}
+
+int c_ofd_lock(int fd, int is_blocking, int is_writable, off_t start, off_t len) {
+#ifdef F_OFD_SETLK
+  return set_lock(fd, is_blocking ? F_OFD_SETLKW : F_OFD_SETLK, is_writable ? F_WRLCK : F_RDLCK, start, len);
+#else
+  (void) fd; (void) is_blocking; (void) is_writable; (void) start; (void) len;
+  return EOPNOTSUPP;
+#endif
+}
+
+int c_ofd_unlock(int fd, off_t start, off_t len) {
+#ifdef F_OFD_SETLK
+  return set_lock(fd, F_OFD_SETLK, F_UNLCK, start, len);
+#else
+  (void) fd; (void) start; (void) len;
+  return EOPNOTSUPP;
+#endif
+}
+
+int c_ofd_getlk(int fd, int is_writable, off_t start, off_t len,
+                int *is_locked, int *is_locked_writable, off_t *locked_start, off_t *locked_len, pid_t *pid) {
+#ifdef F_OFD_GETLK
+  return get_lock(fd, F_OFD_GETLK, is_writable, start, len, is_locked, is_locked_writable, locked_start, locked_len, pid);
+#else
+  (void) fd; (void) is_writable; (void) start; (void) len;
+  (void) is_locked; (void) is_locked_writable; (void) locked_start; (void) locked_len; (void) pid;
+  return EOPNOTSUPP;
+#endif
+}
+
+int c_flock(int fd, int is_blocking, int is_writable) {
   if (fd < 0) {
     return EBADF;
   }
 
-  fl.l_type   = F_UNLCK;
-  fl.l_whence = SEEK_SET;
-  fl.l_start  = 0;
-  fl.l_len    = 0;
+  int operation = is_writable ? LOCK_EX : LOCK_SH;
+
+  if (!is_blocking) {
+    operation |= LOCK_NB;
+  }
 
-  if (fcntl(fd, F_SETLK, &fl) == -1) {
+  if (flock(fd, operation) == -1) {
     return errno;
   }
 
   return 0;
 }
+
+int c_funlock(int fd) {
+  if (fd < 0) {
+    return EBADF;
+  }
+
+  if (flock(fd, LOCK_UN) == -1) {
+    return errno;
+  }
+
+  return 0;
+}
+
+/*
+ * lockf() itself is never called: it locks from the file offset on, which
+ * is shared with every other user of the open file. This is an exclusive
+ * fcntl() record lock on an absolute range instead. POSIX leaves open how
+ * lockf() and fcntl() locks interact, so this only excludes real lockf()
+ * callers on systems which implement one in terms of the other, as Linux
+ * and the BSDs do.
+ */
+int c_lockf(int fd, int is_blocking, off_t start, off_t len) {
+  return set_lock(fd, is_blocking ? F_SETLKW : F_SETLK, F_WRLCK, start, len);
+}
+
+int c_unlockf(int fd, off_t start, off_t len) {
+  return set_lock(fd, F_SETLK, F_UNLCK, start, len);
+}
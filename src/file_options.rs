@@ -1,28 +1,96 @@
+use backend::LockKind;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 
 /// A wrapper around the open options type
-/// that can be queried for the write permissions
+/// that also tells which kind of lock to take out
 ///
 /// This type has the exact same API as [`std::fs::OpenOptions`]
 /// with the exception that the confluent interface passes `self`
-/// rather than `&mut self`.
+/// rather than `&mut self`. This includes the Unix specific
+/// [`mode`](FileOptions::mode) and [`custom_flags`](FileOptions::custom_flags),
+/// and any [`OpenOptions`] can be converted into `FileOptions`.
+///
+/// Unless set with [`FileOptions::lock_kind`], the lock is exclusive if the
+/// file is opened for writing or appending, and shared otherwise.
+///
+/// # Examples
+///
+///```
+///extern crate file_lock;
+///extern crate libc;
+///
+///use file_lock::{FileLock, FileOptions};
+///use std::fs::OpenOptions;
+///
+///fn main() {
+///    // a lock file only we can read, which must not be a symlink
+///    let options = FileOptions::new()
+///                        .write(true)
+///                        .create(true)
+///                        .mode(0o600)
+///                        .custom_flags(libc::O_NOFOLLOW);
+///
+///    match FileLock::lock("myfile.txt", true, options) {
+///        Ok(_) => println!("Got the lock"),
+///        Err(err) => panic!("Error getting write lock: {}", err),
+///    };
+///
+///    // or start from the options you already have
+///    let mut open_options = OpenOptions::new();
+///    open_options.read(true);
+///
+///    match FileLock::lock("myfile.txt", true, open_options.into()) {
+///        Ok(_) => println!("Got the lock"),
+///        Err(err) => panic!("Error getting read lock: {}", err),
+///    };
+///}
+///```
+#[derive(Debug)]
 pub struct FileOptions {
     open_options: OpenOptions,
-    pub(crate) writeable: bool,
+    kind: Option<LockKind>,
+    /// What we know the file is opened for, unless made from [`OpenOptions`]
+    access: Option<Access>,
+    is_truncate: bool,
+    is_create_new: bool,
+}
+
+/// What a file is opened for, which a descriptor must match to be used in place of opening it again
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Access {
+    read: bool,
+    write: bool,
+    append: bool,
+    custom_flags: i32,
+}
+
+impl Access {
+    /// Whether opening the file fails for a symlink
+    pub(crate) fn is_nofollow(&self) -> bool {
+        self.custom_flags & libc::O_NOFOLLOW != 0
+    }
 }
 
 impl FileOptions {
     pub fn new() -> Self {
         Self {
             open_options: OpenOptions::new(),
-            writeable: false,
+            kind: None,
+            access: Some(Access::default()),
+            is_truncate: false,
+            is_create_new: false,
         }
     }
 
     pub fn append(mut self, append: bool) -> Self {
         self.open_options.append(append);
-        self.writeable = true;
+        if let Some(ref mut access) = self.access {
+            access.append = append;
+        }
         self
     }
 
@@ -33,6 +101,7 @@ impl FileOptions {
 
     pub fn create_new(mut self, create_new: bool) -> Self {
         self.open_options.create_new(create_new);
+        self.is_create_new = create_new;
         self
     }
 
@@ -42,20 +111,116 @@ impl FileOptions {
 
     pub fn read(mut self, read: bool) -> Self {
         self.open_options.read(read);
-        self.writeable = read;
+        if let Some(ref mut access) = self.access {
+            access.read = read;
+        }
+        self
+    }
+
+    /// Set the permissions a newly created file gets, see [`OpenOptionsExt::mode`]
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.open_options.mode(mode);
+        self
+    }
+
+    /// Pass extra flags such as `O_NOFOLLOW` or `O_SYNC` to `open()`, see [`OpenOptionsExt::custom_flags`]
+    pub fn custom_flags(mut self, flags: i32) -> Self {
+        self.open_options.custom_flags(flags);
+        if let Some(ref mut access) = self.access {
+            access.custom_flags = flags;
+        }
         self
     }
 
     pub fn truncate(mut self, truncate: bool) -> Self {
         self.open_options.truncate(truncate);
+        self.is_truncate = truncate;
         self
     }
 
     pub fn write(mut self, write: bool) -> Self {
         self.open_options.write(write);
-        self.writeable = write;
+        if let Some(ref mut access) = self.access {
+            access.write = write;
+        }
         self
     }
+
+    /// Take out a lock of the given kind, whatever the file is opened for
+    ///
+    /// The file still has to be opened for reading to be locked shared, and
+    /// for writing to be locked exclusively, otherwise locking fails with
+    /// [`LockError::AccessMode`](enum.LockError.html#variant.AccessMode)
+    /// before even opening the file. Options converted from an
+    /// [`OpenOptions`] can only be checked once the file is open.
+    pub fn lock_kind(mut self, kind: LockKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// What the file is opened for, if an open descriptor of it could be used instead
+    ///
+    /// A file which must be created can't be open already.
+    pub(crate) fn access(&self) -> Option<Access> {
+        match self.is_create_new {
+            true => None,
+            false => self.access,
+        }
+    }
+
+    /// The lock kind asked for, if what the file is opened for is known not to allow it
+    ///
+    /// This can be told before opening the file, which may already create
+    /// or truncate it.
+    pub(crate) fn denied_kind(&self) -> Option<LockKind> {
+        let (kind, access) = match (self.kind, self.access) {
+            (Some(kind), Some(access)) => (kind, access),
+            _ => return None,
+        };
+
+        let is_allowed = match kind {
+            LockKind::Shared => access.read,
+            LockKind::Exclusive => access.write || access.append,
+        };
+
+        match is_allowed {
+            true => None,
+            false => Some(kind),
+        }
+    }
+
+    pub(crate) fn is_truncate(&self) -> bool {
+        self.is_truncate
+    }
+
+    /// The kind of lock to take out on `file`, which has been opened with these options
+    ///
+    /// Going by the access mode `file` ended up with covers options converted
+    /// from an [`OpenOptions`], which can't be asked what they contain.
+    pub(crate) fn kind(&self, file: &File) -> LockKind {
+        if let Some(kind) = self.kind {
+            return kind;
+        }
+
+        match fcntl(file.as_raw_fd(), FcntlArg::F_GETFL) {
+            Ok(flags) if OFlag::from_bits_truncate(flags) & OFlag::O_ACCMODE == OFlag::O_RDONLY => {
+                LockKind::Shared
+            }
+            _ => LockKind::Exclusive,
+        }
+    }
+}
+
+impl From<OpenOptions> for FileOptions {
+    fn from(open_options: OpenOptions) -> Self {
+        Self {
+            open_options,
+            kind: None,
+            access: None,
+            is_truncate: false,
+            is_create_new: false,
+        }
+    }
 }
 
 impl Default for FileOptions {
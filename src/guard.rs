@@ -0,0 +1,224 @@
+use backend::LockBackend;
+use error::LockError;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::time::Duration;
+use FileLock;
+
+/// A shared lock on a whole file, as returned by [`FileLock::lock_shared`](struct.FileLock.html#method.lock_shared)
+///
+/// Other shared locks may be held on the file at the same time, so this only
+/// lets the file be read: it implements [`Read`] and [`Seek`], but gives no
+/// access to the [`File`] itself. The lock is held for as long as the guard
+/// exists, and released on drop or by [`SharedGuard::unlock`], which hands
+/// the file back.
+#[derive(Debug)]
+pub struct SharedGuard {
+    lock: FileLock,
+}
+
+/// An exclusive lock on a whole file, as returned by [`FileLock::lock_exclusive`](struct.FileLock.html#method.lock_exclusive)
+///
+/// Implements [`Read`], [`Write`] and [`Seek`], and derefs to the locked
+/// [`File`] immutably, so it can be both read and written. Functions which
+/// must only run while the file is locked for writing can ask for an
+/// `&mut ExclusiveGuard`. The lock is held for as long as the guard exists,
+/// and released on drop or by [`ExclusiveGuard::unlock`], which hands the
+/// file back.
+///
+/// # Examples
+///
+///```
+///extern crate file_lock;
+///
+///use file_lock::{ExclusiveGuard, FileLock, FileOptions};
+///use std::io::prelude::*;
+///
+///fn append_record(guard: &mut ExclusiveGuard, record: &[u8]) {
+///    guard.write_all(record).is_ok();
+///}
+///
+///fn main() {
+///    let options = FileOptions::new().append(true).create(true);
+///
+///    let mut guard = match FileLock::lock_exclusive("myfile.txt", true, options) {
+///        Ok(guard) => guard,
+///        Err(err) => panic!("Error getting write lock: {}", err),
+///    };
+///
+///    append_record(&mut guard, b"Hello, World!\n");
+///}
+///```
+#[derive(Debug)]
+pub struct ExclusiveGuard {
+    lock: FileLock,
+}
+
+macro_rules! guard {
+    ($guard:ident) => {
+        impl $guard {
+            pub(crate) fn new(lock: FileLock) -> Self {
+                $guard { lock }
+            }
+
+            /// The [`LockBackend`] this lock was taken out with
+            pub fn backend(&self) -> &dyn LockBackend {
+                self.lock.backend()
+            }
+
+            /// How long we had to wait for the lock to become available
+            pub fn waited(&self) -> Duration {
+                self.lock.waited()
+            }
+
+            /// How many attempts it took to get the lock
+            pub fn attempts(&self) -> u32 {
+                self.lock.attempts()
+            }
+
+            /// Unlock the file and hand it back
+            ///
+            /// See [`FileLock::unlock`](struct.FileLock.html#method.unlock) for details.
+            pub fn unlock(self) -> Result<File, LockError> {
+                self.lock.unlock()
+            }
+
+            /// Unlock the file and hand it back, ignoring any error
+            pub fn into_inner(self) -> File {
+                self.lock.into_inner()
+            }
+        }
+    };
+}
+
+guard!(SharedGuard);
+guard!(ExclusiveGuard);
+
+impl SharedGuard {
+    /// Turn our shared lock into an exclusive one
+    ///
+    /// The file must be open for writing. Should the upgrade fail, the
+    /// guard is handed back along with the error, still holding the shared
+    /// lock. Only if the lock was lost along the way is the bare file handed
+    /// back instead, see [`ConvertError`].
+    /// See [`FileLock::upgrade`](struct.FileLock.html#method.upgrade) for details.
+    // handing the guard back is the point, however large it is
+    #[allow(clippy::result_large_err)]
+    pub fn upgrade(mut self, is_blocking: bool) -> Result<ExclusiveGuard, ConvertError<Self>> {
+        match self.lock.upgrade(is_blocking) {
+            Ok(()) => Ok(ExclusiveGuard::new(self.lock)),
+            Err(err) if !self.lock.is_held() => Err(ConvertError::Lost(self.into_inner(), err)),
+            Err(err) => Err(ConvertError::Kept(self, err)),
+        }
+    }
+}
+
+impl ExclusiveGuard {
+    /// Turn our exclusive lock into a shared one
+    ///
+    /// Should the downgrade fail, the guard is handed back along with the
+    /// error, or the bare file if the lock was lost along the way, see
+    /// [`ConvertError`].
+    /// See [`FileLock::downgrade`](struct.FileLock.html#method.downgrade) for details.
+    // handing the guard back is the point, however large it is
+    #[allow(clippy::result_large_err)]
+    pub fn downgrade(mut self) -> Result<SharedGuard, ConvertError<Self>> {
+        match self.lock.downgrade() {
+            Ok(()) => Ok(SharedGuard::new(self.lock)),
+            Err(err) if !self.lock.is_held() => Err(ConvertError::Lost(self.into_inner(), err)),
+            Err(err) => Err(ConvertError::Kept(self, err)),
+        }
+    }
+}
+
+/// Why converting a guard failed, along with what is left of it
+///
+/// As returned by [`SharedGuard::upgrade`] and [`ExclusiveGuard::downgrade`].
+#[derive(Debug)]
+pub enum ConvertError<G> {
+    /// The guard still holds the lock it held before
+    Kept(G, LockError),
+    /// The lock is gone, as told by [`LockError::Lost`](enum.LockError.html#variant.Lost),
+    /// so only the file is left
+    Lost(File, LockError),
+}
+
+impl<G> ConvertError<G> {
+    /// Why the conversion failed
+    pub fn error(&self) -> &LockError {
+        match *self {
+            ConvertError::Kept(_, ref err) | ConvertError::Lost(_, ref err) => err,
+        }
+    }
+}
+
+impl<G> From<ConvertError<G>> for LockError {
+    fn from(err: ConvertError<G>) -> LockError {
+        match err {
+            ConvertError::Kept(_, err) | ConvertError::Lost(_, err) => err,
+        }
+    }
+}
+
+impl<G> fmt::Display for ConvertError<G> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.error(), f)
+    }
+}
+
+impl<G: fmt::Debug> error::Error for ConvertError<G> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        error::Error::source(self.error())
+    }
+}
+
+impl From<ExclusiveGuard> for FileLock {
+    fn from(guard: ExclusiveGuard) -> FileLock {
+        guard.lock
+    }
+}
+
+impl Read for SharedGuard {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock.read(buf)
+    }
+}
+
+impl Seek for SharedGuard {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.lock.seek(pos)
+    }
+}
+
+impl Deref for ExclusiveGuard {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.lock
+    }
+}
+
+impl Read for ExclusiveGuard {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock.read(buf)
+    }
+}
+
+impl Write for ExclusiveGuard {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock.flush()
+    }
+}
+
+impl Seek for ExclusiveGuard {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.lock.seek(pos)
+    }
+}
@@ -4,6 +4,14 @@
 //! following the advisory record lock scheme as specified by UNIX IEEE Std 1003.1-2001
 //! (POSIX.1) via `fcntl()`.
 //!
+//! Open file description locks, `flock()` and `lockf()` are available as well
+//! through [`LockMode`], and further mechanisms can be plugged in by
+//! implementing [`LockBackend`].
+//!
+//! Enabling the `tokio` feature adds `AsyncFileLock`, which waits for locks
+//! without blocking the threads of a tokio runtime. The `async` feature
+//! adds `FileLock::lock_async` instead, which works with any executor.
+//!
 //! # Examples
 //!
 //! Please note that the examples use `tempfile` merely to quickly create a file
@@ -29,7 +37,7 @@
 //!         Err(err) => panic!("Error getting write lock: {}", err),
 //!     };
 //!
-//!     filelock.file.write_all(b"Hello, World!").is_ok();
+//!     filelock.write_all(b"Hello, World!").is_ok();
 //!
 //!     // Manually unlocking is optional as we unlock on Drop
 //!     filelock.unlock();
@@ -37,33 +45,91 @@
 //! ```
 
 extern crate libc;
+extern crate mktemp;
 extern crate nix;
+#[cfg(feature = "tokio")]
+extern crate tokio;
 
+#[cfg(any(feature = "async", feature = "tokio"))]
+mod acquisition;
+pub mod backend;
+mod borrowed;
+mod cancel;
+mod error;
 mod file_options;
+mod guard;
+#[cfg(feature = "async")]
+mod lock_future;
+mod lock_mode;
+mod lock_options;
+mod lock_state;
+mod registry;
+mod replace;
+mod retry;
+mod sidecar;
+#[cfg(feature = "tokio")]
+mod tokio_lock;
+mod wait;
+mod waiter;
 
-use libc::c_int;
 use std::fs::File;
-use std::io::Error;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::AsRawFd;
 use std::path::Path;
+use std::time::Duration;
 
+pub use backend::{LockBackend, LockInfo, LockKind};
+pub use borrowed::BorrowedFileLock;
+pub use cancel::CancelToken;
+pub use error::{LockError, Operation};
 pub use file_options::FileOptions;
+pub use guard::{ConvertError, ExclusiveGuard, SharedGuard};
+#[cfg(feature = "async")]
+pub use lock_future::LockFuture;
+pub use lock_mode::LockMode;
+pub use lock_options::LockOptions;
+pub use retry::RetryPolicy;
+pub use sidecar::{Sidecar, SidecarGuard};
+#[cfg(feature = "tokio")]
+pub use tokio_lock::{AsyncFileLock, AsyncLockFuture};
+pub use waiter::LockWaiter;
 
-extern "C" {
-    fn c_lock(fd: i32, is_blocking: i32, is_writeable: i32) -> c_int;
-    fn c_unlock(fd: i32) -> c_int;
-}
+#[cfg(feature = "async")]
+use acquisition::Acquisition;
+use file_options::Access;
+use lock_state::LockState;
+use registry::{Owner, Registered};
+use wait::Wait;
 
-/// Represents the actually locked file
+/// Represents the actually locked file, or the locked region of it
+///
+/// Implements [`Read`], [`Write`] and [`Seek`], and derefs to the locked
+/// [`File`], though only immutably: replacing the file would leave the lock
+/// behind on a file nobody uses. The lock is held for as long as this
+/// exists, and released on drop or by [`FileLock::unlock`], which hands the
+/// file back.
+///
+/// Closing any descriptor of a file releases all POSIX record locks the
+/// process holds on it. So that several `FileLock`s on the same file can
+/// come and go independently, a `FileLock` only unlocks what no other one
+/// holds, and the descriptors of a file stay open until the last lock on it
+/// is gone. Locking or querying the file by path reuses them in the
+/// meantime, unless the [`FileOptions`] were converted from an `OpenOptions`.
 #[derive(Debug)]
 pub struct FileLock {
-    /// the `std::fs::File` of the file that's locked
-    pub file: File,
+    file: ManuallyDrop<File>,
+    state: LockState,
 }
 
 impl FileLock {
     /// Try to lock the specified file
     ///
+    /// This is a shorthand for [`LockOptions`], which covers every other way
+    /// of taking out a lock as well.
+    ///
     /// # Parameters
     ///
     /// `path` is the path of the file we want to lock on
@@ -93,7 +159,7 @@ impl FileLock {
     ///        Err(err) => panic!("Error getting write lock: {}", err),
     ///    };
     ///
-    ///    filelock.file.write_all(b"Hello, World!").is_ok();
+    ///    filelock.write_all(b"Hello, World!").is_ok();
     ///}
     ///```
     ///
@@ -101,21 +167,124 @@ impl FileLock {
         path: P,
         is_blocking: bool,
         options: FileOptions,
-    ) -> Result<FileLock, Error> {
-        let file = options.open(path)?;
-        let is_writeable = options.writeable;
+    ) -> Result<FileLock, LockError> {
+        Self::lock_range(path, is_blocking, options, 0, 0)
+    }
 
-        let errno = unsafe { c_lock(file.as_raw_fd(), is_blocking as i32, is_writeable as i32) };
+    /// Try to lock a byte range of the specified file
+    ///
+    /// Other processes may lock and unlock disjoint ranges of the same file
+    /// concurrently, which allows several of them to update different records
+    /// of one data file at the same time. Whether the range is locked shared
+    /// (for reading) or exclusive (for writing) follows `options`, just like
+    /// with [`FileLock::lock`].
+    ///
+    /// # Parameters
+    ///
+    /// `path` is the path of the file we want to lock on
+    ///
+    /// `is_blocking` is a flag to indicate if we should block if the range is already locked
+    ///
+    /// `options` configures the underlying file
+    ///
+    /// `start` is the offset of the first byte to lock
+    ///
+    /// `len` is the number of bytes to lock. A `len` of `0` locks from `start`
+    /// to the end of the file, however large the file grows.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///extern crate file_lock;
+    ///
+    ///use file_lock::{FileLock, FileOptions};
+    ///use std::io::prelude::*;
+    ///use std::io::SeekFrom;
+    ///
+    ///fn main() {
+    ///    let options = FileOptions::new().write(true).create(true);
+    ///
+    ///    // Lock the second 64 byte record only
+    ///    let mut filelock = match FileLock::lock_range("records.bin", true, options, 64, 64) {
+    ///        Ok(lock) => lock,
+    ///        Err(err) => panic!("Error getting write lock: {}", err),
+    ///    };
+    ///
+    ///    filelock.seek(SeekFrom::Start(64)).is_ok();
+    ///    filelock.write_all(&[0u8; 64]).is_ok();
+    ///}
+    ///```
+    ///
+    pub fn lock_range<P: AsRef<Path>>(
+        path: P,
+        is_blocking: bool,
+        options: FileOptions,
+        start: u64,
+        len: u64,
+    ) -> Result<FileLock, LockError> {
+        Self::lock_range_with(path, is_blocking, options, start, len, LockMode::Posix)
+    }
 
-        match errno {
-            0 => Ok(FileLock { file }),
-            _ => Err(Error::from_raw_os_error(errno)),
-        }
+    /// Try to lock the specified file using the given [`LockBackend`]
+    ///
+    /// This behaves like [`FileLock::lock`], but allows to choose the locking
+    /// mechanism, usually one of the [`LockMode`]s. With [`LockMode::Ofd`] the
+    /// lock belongs to the opened file rather than the process, so it also
+    /// excludes other threads of the same process. [`LockMode::Flock`]
+    /// interoperates with `flock(1)` instead of `fcntl` locks.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///extern crate file_lock;
+    ///
+    ///use file_lock::{FileLock, FileOptions, LockMode};
+    ///use std::io::prelude::*;
+    ///
+    ///fn main() {
+    ///    let options = FileOptions::new().write(true).create(true);
+    ///
+    ///    let mut filelock = match FileLock::lock_with("myfile.txt", true, options, LockMode::Ofd) {
+    ///        Ok(lock) => lock,
+    ///        Err(err) => panic!("Error getting write lock: {}", err),
+    ///    };
+    ///
+    ///    filelock.write_all(b"Hello, World!").is_ok();
+    ///}
+    ///```
+    ///
+    pub fn lock_with<P: AsRef<Path>, B: LockBackend + 'static>(
+        path: P,
+        is_blocking: bool,
+        options: FileOptions,
+        backend: B,
+    ) -> Result<FileLock, LockError> {
+        Self::lock_range_with(path, is_blocking, options, 0, 0, backend)
     }
 
-    /// Unlock our locked file
+    /// Try to lock a byte range of the specified file using the given [`LockBackend`]
     ///
-    /// *Note:* This method is optional as the file lock will be unlocked automatically when dropped
+    /// See [`FileLock::lock_range`] and [`FileLock::lock_with`] for details.
+    pub fn lock_range_with<P: AsRef<Path>, B: LockBackend + 'static>(
+        path: P,
+        is_blocking: bool,
+        options: FileOptions,
+        start: u64,
+        len: u64,
+        backend: B,
+    ) -> Result<FileLock, LockError> {
+        LockOptions::new()
+            .blocking(is_blocking)
+            .range(start, len)
+            .backend(backend)
+            .file_options(options)
+            .lock(path)
+    }
+
+    /// Take out a shared lock on the specified file, whatever `options` ask for
+    ///
+    /// The file must be opened for reading. Returns a [`SharedGuard`], which
+    /// only lets the file be read.
     ///
     /// # Examples
     ///
@@ -126,114 +295,811 @@ impl FileLock {
     ///use std::io::prelude::*;
     ///
     ///fn main() {
-    ///    let should_we_block  = true;
-    ///    let lock_for_writing = FileOptions::new().write(true).create(true);
+    ///    let options = FileOptions::new().read(true).write(true).create(true);
     ///
-    ///    let mut filelock = match FileLock::lock("myfile.txt", should_we_block, lock_for_writing) {
+    ///    let mut guard = match FileLock::lock_shared("myfile.txt", true, options) {
+    ///        Ok(guard) => guard,
+    ///        Err(err) => panic!("Error getting read lock: {}", err),
+    ///    };
+    ///
+    ///    let mut contents = String::new();
+    ///    guard.read_to_string(&mut contents).is_ok();
+    ///
+    ///    // The file is still ours once the lock is gone
+    ///    let _file = guard.into_inner();
+    ///}
+    ///```
+    ///
+    pub fn lock_shared<P: AsRef<Path>>(
+        path: P,
+        is_blocking: bool,
+        options: FileOptions,
+    ) -> Result<SharedGuard, LockError> {
+        let wait = Wait::from_blocking(is_blocking);
+        let options = options.lock_kind(LockKind::Shared);
+        let lock = Self::acquire(path, wait, options, 0, 0, LockMode::Posix)?;
+
+        Ok(SharedGuard::new(lock))
+    }
+
+    /// Take out an exclusive lock on the specified file, whatever `options` ask for
+    ///
+    /// The file must be opened for writing. Returns an [`ExclusiveGuard`],
+    /// which gives full access to the file.
+    ///
+    /// # Examples
+    ///
+    /// See [`ExclusiveGuard`].
+    pub fn lock_exclusive<P: AsRef<Path>>(
+        path: P,
+        is_blocking: bool,
+        options: FileOptions,
+    ) -> Result<ExclusiveGuard, LockError> {
+        let wait = Wait::from_blocking(is_blocking);
+        let options = options.lock_kind(LockKind::Exclusive);
+        let lock = Self::acquire(path, wait, options, 0, 0, LockMode::Posix)?;
+
+        Ok(ExclusiveGuard::new(lock))
+    }
+
+    /// Try to lock the specified file, waiting at most `timeout` for it to become available
+    ///
+    /// If the file is still locked by someone else once `timeout` has passed,
+    /// this fails with [`LockError::TimedOut`], which tells how long we
+    /// actually waited.
+    ///
+    /// Waiting is implemented by retrying with exponential backoff up to 50ms
+    /// between attempts, and measured with a monotonic clock.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///extern crate file_lock;
+    ///
+    ///use file_lock::{FileLock, FileOptions};
+    ///use std::io::prelude::*;
+    ///use std::time::Duration;
+    ///
+    ///fn main() {
+    ///    let options = FileOptions::new().write(true).create(true);
+    ///
+    ///    let mut filelock = match FileLock::lock_timeout("myfile.txt", Duration::from_secs(30), options) {
     ///        Ok(lock) => lock,
     ///        Err(err) => panic!("Error getting write lock: {}", err),
     ///    };
     ///
-    ///    filelock.file.write_all(b"Hello, World!").is_ok();
+    ///    filelock.write_all(b"Hello, World!").is_ok();
+    ///}
+    ///```
+    ///
+    pub fn lock_timeout<P: AsRef<Path>>(
+        path: P,
+        timeout: Duration,
+        options: FileOptions,
+    ) -> Result<FileLock, LockError> {
+        Self::lock_range_timeout_with(path, timeout, options, 0, 0, LockMode::Posix)
+    }
+
+    /// Try to lock a byte range of the specified file using the given
+    /// [`LockBackend`], waiting at most `timeout` for it to become available
     ///
-    ///    match filelock.unlock() {
-    ///        Ok(_)    => println!("Successfully unlocked the file"),
-    ///        Err(err) => panic!("Error unlocking the file: {}", err),
+    /// See [`FileLock::lock_timeout`] and [`FileLock::lock_range_with`] for details.
+    pub fn lock_range_timeout_with<P: AsRef<Path>, B: LockBackend + 'static>(
+        path: P,
+        timeout: Duration,
+        options: FileOptions,
+        start: u64,
+        len: u64,
+        backend: B,
+    ) -> Result<FileLock, LockError> {
+        LockOptions::new()
+            .timeout(timeout)
+            .range(start, len)
+            .backend(backend)
+            .file_options(options)
+            .lock(path)
+    }
+
+    /// Try to lock the specified file, waiting until it becomes available or `cancel` is triggered
+    ///
+    /// Once [`CancelToken::cancel`] is called from another thread, this
+    /// returns [`LockError::Cancelled`] right away, without holding any lock.
+    /// Should the lock be acquired at the very moment of cancellation, it is
+    /// returned as usual.
+    ///
+    /// Like with [`FileLock::lock_timeout`], waiting is implemented by retrying
+    /// with exponential backoff up to 50ms between attempts.
+    ///
+    /// # Examples
+    ///
+    /// See [`CancelToken`].
+    pub fn lock_cancellable<P: AsRef<Path>>(
+        path: P,
+        cancel: &CancelToken,
+        options: FileOptions,
+    ) -> Result<FileLock, LockError> {
+        Self::lock_range_cancellable_with(path, cancel, options, 0, 0, LockMode::Posix)
+    }
+
+    /// Try to lock a byte range of the specified file using the given
+    /// [`LockBackend`], waiting until it becomes available or `cancel` is triggered
+    ///
+    /// See [`FileLock::lock_cancellable`] and [`FileLock::lock_range_with`] for details.
+    pub fn lock_range_cancellable_with<P: AsRef<Path>, B: LockBackend + 'static>(
+        path: P,
+        cancel: &CancelToken,
+        options: FileOptions,
+        start: u64,
+        len: u64,
+        backend: B,
+    ) -> Result<FileLock, LockError> {
+        LockOptions::new()
+            .cancellable(cancel)
+            .range(start, len)
+            .backend(backend)
+            .file_options(options)
+            .lock(path)
+    }
+
+    /// Lock the specified file from async code, whatever the executor
+    ///
+    /// Takes the same parameters as [`FileLock::lock`]. See [`LockFuture`]
+    /// for how waiting works.
+    ///
+    /// Only available with the `async` feature.
+    #[cfg(feature = "async")]
+    pub fn lock_async<P: AsRef<Path>>(
+        path: P,
+        is_blocking: bool,
+        options: FileOptions,
+    ) -> LockFuture {
+        Self::lock_async_with(path, is_blocking, options, LockMode::Posix)
+    }
+
+    /// Lock the specified file from async code using the given [`LockBackend`]
+    ///
+    /// See [`FileLock::lock_async`] for details.
+    #[cfg(feature = "async")]
+    pub fn lock_async_with<P: AsRef<Path>, B: LockBackend + 'static>(
+        path: P,
+        is_blocking: bool,
+        options: FileOptions,
+        backend: B,
+    ) -> LockFuture {
+        LockFuture::new(Acquisition::new(
+            path.as_ref(),
+            is_blocking,
+            options,
+            backend,
+        ))
+    }
+
+    /// Lock the specified file from async code, waiting at most `timeout`
+    ///
+    /// See [`FileLock::lock_async`] and [`FileLock::lock_timeout`] for details.
+    #[cfg(feature = "async")]
+    pub fn lock_timeout_async<P: AsRef<Path>>(
+        path: P,
+        timeout: Duration,
+        options: FileOptions,
+    ) -> LockFuture {
+        LockFuture::new(Acquisition::timeout(
+            path.as_ref(),
+            timeout,
+            options,
+            LockMode::Posix,
+        ))
+    }
+
+    /// Lock an already open file
+    ///
+    /// This is useful for files which can't be opened by path, such as
+    /// temporary files, files created with `O_TMPFILE` or received over a
+    /// Unix socket. Anything which converts into a [`File`], like an
+    /// [`std::os::unix::io::OwnedFd`], is accepted. The file is unlocked on
+    /// drop, just like with [`FileLock::lock`].
+    ///
+    /// # Parameters
+    ///
+    /// `file` is the open file we want to lock
+    ///
+    /// `is_blocking` is a flag to indicate if we should block if it's already locked
+    ///
+    /// `kind` is whether to take out a shared or an exclusive lock, which
+    /// must match the access mode `file` has been opened with
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///extern crate file_lock;
+    ///
+    ///use file_lock::{FileLock, LockKind};
+    ///use std::fs::OpenOptions;
+    ///use std::io::prelude::*;
+    ///
+    ///fn main() {
+    ///    let file = OpenOptions::new().write(true).create(true).open("myfile.txt").unwrap();
+    ///
+    ///    let mut filelock = match FileLock::lock_file(file, true, LockKind::Exclusive) {
+    ///        Ok(lock) => lock,
+    ///        Err(err) => panic!("Error getting write lock: {}", err),
     ///    };
+    ///
+    ///    filelock.write_all(b"Hello, World!").is_ok();
     ///}
     ///```
     ///
-    pub fn unlock(&self) -> Result<(), Error> {
-        let errno = unsafe { c_unlock(self.file.as_raw_fd()) };
+    pub fn lock_file<F: Into<File>>(
+        file: F,
+        is_blocking: bool,
+        kind: LockKind,
+    ) -> Result<FileLock, LockError> {
+        Self::lock_file_with(file, is_blocking, kind, LockMode::Posix)
+    }
 
-        match errno {
-            0 => Ok(()),
-            _ => Err(Error::from_raw_os_error(errno)),
-        }
+    /// Lock an already open file using the given [`LockBackend`]
+    ///
+    /// See [`FileLock::lock_file`] and [`FileLock::lock_with`] for details.
+    pub fn lock_file_with<F: Into<File>, B: LockBackend + 'static>(
+        file: F,
+        is_blocking: bool,
+        kind: LockKind,
+        backend: B,
+    ) -> Result<FileLock, LockError> {
+        LockOptions::new()
+            .kind(kind)
+            .blocking(is_blocking)
+            .backend(backend)
+            .lock_file(file)
     }
-}
 
-impl Drop for FileLock {
-    fn drop(&mut self) {
-        let _ = self.unlock().is_ok();
+    /// Replace the contents of the specified file all at once, under a lock on its sidecar
+    ///
+    /// A shorthand for [`LockOptions::replace`], which has the details.
+    pub fn replace<P, T, F>(path: P, write: F) -> Result<T, LockError>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut File) -> std::io::Result<T>,
+    {
+        LockOptions::new().replace(path, write)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    pub(crate) fn acquire<P: AsRef<Path>, B: LockBackend + 'static>(
+        path: P,
+        wait: Wait,
+        options: FileOptions,
+        start: u64,
+        len: u64,
+        backend: B,
+    ) -> Result<FileLock, LockError> {
+        Self::open_and_lock(path.as_ref(), wait, &options, start, len, Box::new(backend))
+    }
 
-    use nix::unistd::fork;
-    use nix::unistd::ForkResult::{Child, Parent};
-    use std::fs::{remove_file, OpenOptions};
-    use std::process;
-    use std::thread::sleep;
-    use std::time::Duration;
+    /// Like [`FileLock::acquire`], but make sure we end up with a lock on
+    /// the file which is at `path` while we hold it
+    ///
+    /// Should the file have been removed or replaced before we got the lock,
+    /// the lock is given up and taken out again on whatever file is at
+    /// `path` now. Any timeout covers all of this.
+    pub(crate) fn acquire_verified<P: AsRef<Path>, B: LockBackend + 'static>(
+        path: P,
+        mut wait: Wait,
+        options: FileOptions,
+        start: u64,
+        len: u64,
+        backend: B,
+    ) -> Result<FileLock, LockError> {
+        let path = path.as_ref();
+        let mut backend: Box<dyn LockBackend> = Box::new(backend);
+        let mut waited = Duration::ZERO;
+        let mut attempts = 0;
 
-    fn standard_options(is_writable: &bool) -> FileOptions {
-        FileOptions::new()
-            .read(!*is_writable)
-            .write(*is_writable)
-            .create(*is_writable)
+        loop {
+            let mut lock = Self::open_and_lock(path, wait.clone(), &options, start, len, backend)?;
+            waited += lock.state.waited;
+            attempts += lock.state.attempts;
+
+            if lock.is_at(path)? {
+                lock.state.waited = waited;
+                lock.state.attempts = attempts;
+                return Ok(lock);
+            }
+
+            wait = wait.after(lock.state.waited);
+            let (file, state) = lock.into_parts();
+            let _ = state.release(file.as_raw_fd()).is_ok();
+            registry::close(file, state.access);
+            backend = state.backend;
+        }
     }
 
-    #[test]
-    fn lock_and_unlock() {
-        let filename = "filelock.test";
+    fn open_and_lock(
+        path: &Path,
+        wait: Wait,
+        options: &FileOptions,
+        start: u64,
+        len: u64,
+        backend: Box<dyn LockBackend>,
+    ) -> Result<FileLock, LockError> {
+        if let Some(kind) = options
+            .denied_kind()
+            .filter(|_| backend.needs_access_mode())
+        {
+            return Err(LockError::AccessMode {
+                operation: Operation::Lock,
+                path: Some(path.to_path_buf()),
+                kind,
+            });
+        }
 
-        for already_exists in &[true, false] {
-            for already_locked in &[true, false] {
-                for already_writable in &[true, false] {
-                    for is_blocking in &[true, false] {
-                        for is_writable in &[true, false] {
-                            if !*already_exists && (*already_locked || *already_writable) {
-                                // nonsensical tests
-                                continue;
-                            }
+        let file = registry::open(path, options).map_err(|source| LockError::Open {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let kind = options.kind(&file);
+        let fd = file.as_raw_fd();
+        let locked = LockState::acquire(
+            fd,
+            kind,
+            wait,
+            start,
+            len,
+            backend,
+            Some(path.to_path_buf()),
+        );
 
-                            let _ = remove_file(&filename).is_ok();
+        Self::new(file, options.access(), locked)
+    }
 
-                            let parent_lock = match *already_exists {
-                                false => None,
-                                true => {
-                                    OpenOptions::new()
-                                        .write(true)
-                                        .create(true)
-                                        .open(&filename)
-                                        .expect("Test failed");
+    pub(crate) fn acquire_file<B: LockBackend + 'static>(
+        file: File,
+        wait: Wait,
+        kind: LockKind,
+        start: u64,
+        len: u64,
+        backend: B,
+    ) -> Result<FileLock, LockError> {
+        let locked = LockState::acquire(
+            file.as_raw_fd(),
+            kind,
+            wait,
+            start,
+            len,
+            Box::new(backend),
+            None,
+        );
 
-                                    match *already_locked {
-                                        false => None,
-                                        true => {
-                                            let options = standard_options(already_writable);
-                                            match FileLock::lock(filename, true, options) {
-                                                Ok(lock) => Some(lock),
-                                                Err(err) => {
-                                                    panic!("Error creating parent lock ({})", err)
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            };
+        Self::new(file, None, locked)
+    }
 
-                            unsafe {
-                                match fork() {
-                                    Ok(Parent { child: _ }) => {
-                                        sleep(Duration::from_millis(150));
+    /// Whether `path` still refers to the file we hold the lock on
+    fn is_at(&self, path: &Path) -> Result<bool, LockError> {
+        let at_path = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(LockError::from_io(Operation::Lock, err).with_path(Some(path))),
+        };
+        let locked = self
+            .file
+            .metadata()
+            .map_err(|err| LockError::from_io(Operation::Lock, err).with_path(Some(path)))?;
 
-                                        if let Some(lock) = parent_lock {
-                                            lock.unlock().expect("Test failed");
-                                        }
+        Ok(at_path.dev() == locked.dev() && at_path.ino() == locked.ino())
+    }
 
-                                        sleep(Duration::from_millis(350));
-                                    }
-                                    Ok(Child) => {
-                                        let mut try_count = 0;
-                                        let mut locked = false;
+    /// `access` tells what `file` was opened for, if by [`registry::open`]
+    fn new(
+        file: File,
+        access: Option<Access>,
+        locked: Result<LockState, LockError>,
+    ) -> Result<FileLock, LockError> {
+        match locked {
+            Ok(state) => Ok(FileLock {
+                file: ManuallyDrop::new(file),
+                state: LockState { access, ..state },
+            }),
+            Err(err) => {
+                registry::close(file, access);
+                Err(err)
+            }
+        }
+    }
 
-                                        match *already_locked {
-                                            true => match *is_blocking {
+    /// Find out who holds a lock on the specified file which would prevent us from locking it
+    ///
+    /// Returns `None` if the range could be locked right now. POSIX record
+    /// locks held by the calling process are only reported if they were
+    /// taken out through a `FileLock`, with our own process id.
+    ///
+    /// # Parameters
+    ///
+    /// `path` is the path of the file we want to know about
+    ///
+    /// `kind` is the kind of lock we would like to take out
+    ///
+    /// `start` and `len` describe the range we would like to lock, like with [`FileLock::lock_range`]
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///extern crate file_lock;
+    ///
+    ///use file_lock::{FileLock, LockKind};
+    ///
+    ///fn main() {
+    ///    match FileLock::query("myfile.txt", LockKind::Exclusive, 0, 0) {
+    ///        Ok(Some(holder)) => println!("{}", holder),
+    ///        Ok(None) => println!("Not locked"),
+    ///        Err(err) => panic!("Error querying the lock: {}", err),
+    ///    };
+    ///}
+    ///```
+    ///
+    pub fn query<P: AsRef<Path>>(
+        path: P,
+        kind: LockKind,
+        start: u64,
+        len: u64,
+    ) -> Result<Option<LockInfo>, LockError> {
+        Self::query_with(path, kind, start, len, LockMode::Posix)
+    }
+
+    /// Find out who holds a lock on the specified file using the given [`LockBackend`]
+    ///
+    /// With [`LockMode::Ofd`] this uses `F_OFD_GETLK`, which also reports
+    /// conflicting locks held by other open files of the calling process.
+    /// See [`FileLock::query`] for details.
+    pub fn query_with<P: AsRef<Path>, B: LockBackend>(
+        path: P,
+        kind: LockKind,
+        start: u64,
+        len: u64,
+        backend: B,
+    ) -> Result<Option<LockInfo>, LockError> {
+        let path = path.as_ref();
+        let options = FileOptions::new().read(true);
+        let file = registry::open(path, &options).map_err(|source| LockError::Open {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        let holder = Self::query_file(&file, kind, start, len, backend);
+        registry::close(file, options.access());
+
+        holder.map_err(|err| err.with_path(Some(path)))
+    }
+
+    /// Find out who holds a lock on the already open `file` using the given [`LockBackend`]
+    ///
+    /// See [`FileLock::query`] for details.
+    pub fn query_file<B: LockBackend>(
+        file: &File,
+        kind: LockKind,
+        start: u64,
+        len: u64,
+        backend: B,
+    ) -> Result<Option<LockInfo>, LockError> {
+        let fd = file.as_raw_fd();
+        let registered = Registered {
+            inner: &backend,
+            owner: Owner::new(&backend, fd)
+                .map_err(|err| LockError::from_io(Operation::Query, err))?,
+        };
+
+        registered
+            .query(fd, kind, start, len)
+            .map_err(|err| LockError::from_io(Operation::Query, err))
+    }
+
+    /// The [`LockBackend`] this lock was taken out with
+    pub fn backend(&self) -> &dyn LockBackend {
+        &*self.state.backend
+    }
+
+    /// The locked region as `(start, len)`, where a `len` of `0` means up to the end of the file
+    pub fn range(&self) -> (u64, u64) {
+        (self.state.start, self.state.len)
+    }
+
+    /// How long we had to wait for the lock to become available
+    pub fn waited(&self) -> Duration {
+        self.state.waited
+    }
+
+    /// How many attempts it took to get the lock
+    ///
+    /// This is always `1` unless retrying as told by a [`RetryPolicy`].
+    pub fn attempts(&self) -> u32 {
+        self.state.attempts
+    }
+
+    /// Whether we currently hold a shared or an exclusive lock
+    ///
+    /// Once the lock is lost, this is the kind we held last.
+    pub fn kind(&self) -> LockKind {
+        self.state.kind
+    }
+
+    /// Whether we still hold our lock
+    ///
+    /// This is only ever `false` after converting the lock failed with
+    /// [`LockError::Lost`], until a later conversion succeeds.
+    pub fn is_held(&self) -> bool {
+        self.state.is_held
+    }
+
+    /// Turn our shared lock into an exclusive one
+    ///
+    /// With `fcntl()` based backends the conversion is atomic: no other
+    /// writer can slip in between, and if the exclusive lock can't be had,
+    /// the shared lock is kept. [`LockMode::Flock`] converts by unlocking and
+    /// relocking, so that guarantee only holds for it on a best effort basis.
+    /// Should it not even get the shared lock back, this fails with
+    /// [`LockError::Lost`] and no lock is held until a later conversion
+    /// succeeds, see [`FileLock::is_held`].
+    ///
+    /// The file must be open for writing.
+    ///
+    /// `is_blocking` is a flag to indicate if we should block if other shared locks are held
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///extern crate file_lock;
+    ///
+    ///use file_lock::{FileLock, FileOptions};
+    ///use std::io::prelude::*;
+    ///
+    ///fn main() {
+    ///    let options = FileOptions::new().read(true).write(true).create(true);
+    ///
+    ///    let mut filelock = match FileLock::lock("myfile.txt", true, options) {
+    ///        Ok(lock) => lock,
+    ///        Err(err) => panic!("Error getting lock: {}", err),
+    ///    };
+    ///
+    ///    // Let other readers in while we don't need to write
+    ///    filelock.downgrade().expect("Error downgrading the lock");
+    ///
+    ///    let mut contents = String::new();
+    ///    filelock.read_to_string(&mut contents).is_ok();
+    ///
+    ///    match filelock.upgrade(true) {
+    ///        Ok(_) => filelock.write_all(b"Hello, World!").is_ok(),
+    ///        Err(err) => panic!("Error upgrading the lock: {}", err),
+    ///    };
+    ///}
+    ///```
+    ///
+    pub fn upgrade(&mut self, is_blocking: bool) -> Result<(), LockError> {
+        let fd = self.file.as_raw_fd();
+        self.state
+            .convert(fd, LockKind::Exclusive, Wait::from_blocking(is_blocking))
+    }
+
+    /// Turn our shared lock into an exclusive one, waiting at most `timeout`
+    ///
+    /// See [`FileLock::upgrade`] and [`FileLock::lock_timeout`] for details.
+    pub fn upgrade_timeout(&mut self, timeout: Duration) -> Result<(), LockError> {
+        let fd = self.file.as_raw_fd();
+        self.state
+            .convert(fd, LockKind::Exclusive, Wait::timeout(timeout))
+    }
+
+    /// Turn our exclusive lock into a shared one
+    ///
+    /// With `fcntl()` based backends this never has to wait, and no other
+    /// writer can slip in between. The file must be open for reading.
+    pub fn downgrade(&mut self) -> Result<(), LockError> {
+        let fd = self.file.as_raw_fd();
+        self.state.convert(fd, LockKind::Shared, Wait::Blocking)
+    }
+
+    /// Unlock our locked file and hand it back
+    ///
+    /// Should unlocking fail, the file is closed all the same, which releases
+    /// any `fcntl()` lock on it. Closing the returned file releases the POSIX
+    /// record locks of other `FileLock`s on the same file as well, so keep it
+    /// open for as long as they are needed, or just drop the `FileLock`.
+    ///
+    /// *Note:* This method is optional as the file lock will be unlocked automatically when dropped
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///extern crate file_lock;
+    ///
+    ///use file_lock::{FileLock, FileOptions};
+    ///use std::io::prelude::*;
+    ///
+    ///fn main() {
+    ///    let should_we_block  = true;
+    ///    let lock_for_writing = FileOptions::new().write(true).create(true);
+    ///
+    ///    let mut filelock = match FileLock::lock("myfile.txt", should_we_block, lock_for_writing) {
+    ///        Ok(lock) => lock,
+    ///        Err(err) => panic!("Error getting write lock: {}", err),
+    ///    };
+    ///
+    ///    filelock.write_all(b"Hello, World!").is_ok();
+    ///
+    ///    let mut file = match filelock.unlock() {
+    ///        Ok(file) => file,
+    ///        Err(err) => panic!("Error unlocking the file: {}", err),
+    ///    };
+    ///
+    ///    file.write_all(b"No longer locked").is_ok();
+    ///}
+    ///```
+    ///
+    pub fn unlock(self) -> Result<File, LockError> {
+        let (file, state) = self.into_parts();
+
+        match state.release(file.as_raw_fd()) {
+            Ok(()) => Ok(file),
+            Err(err) => {
+                registry::close(file, state.access);
+                Err(err)
+            }
+        }
+    }
+
+    /// Unlock our locked file and hand it back, ignoring any error
+    ///
+    /// See [`FileLock::unlock`] for details.
+    pub fn into_inner(self) -> File {
+        let (file, state) = self.into_parts();
+        let _ = state.release(file.as_raw_fd()).is_ok();
+
+        file
+    }
+
+    /// Take the file and the lock held on it apart, without unlocking
+    pub(crate) fn into_parts(self) -> (File, LockState) {
+        let lock = std::mem::ManuallyDrop::new(self);
+
+        // `lock` is never dropped, so each field is moved out exactly once
+        unsafe {
+            (
+                ManuallyDrop::into_inner(std::ptr::read(&lock.file)),
+                std::ptr::read(&lock.state),
+            )
+        }
+    }
+}
+
+impl Deref for FileLock {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Read for FileLock {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.file).read(buf)
+    }
+}
+
+impl Write for FileLock {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self.file).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self.file).flush()
+    }
+}
+
+impl Seek for FileLock {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        (&*self.file).seek(pos)
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let _ = self.state.release(self.file.as_raw_fd()).is_ok();
+
+        // `self.file` is never used again
+        registry::close(
+            unsafe { ManuallyDrop::take(&mut self.file) },
+            self.state.access,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use nix::sys::wait::{waitpid, WaitStatus};
+    use nix::unistd::fork;
+    use nix::unistd::ForkResult::{Child, Parent};
+    use std::fs::{remove_file, OpenOptions};
+    use std::process;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    /// Runs `check` in a forked child and reports whether it returned `true` there
+    fn in_child<F: FnOnce() -> bool>(check: F) -> bool {
+        unsafe {
+            match fork() {
+                Ok(Parent { child }) => {
+                    matches!(waitpid(child, None), Ok(WaitStatus::Exited(_, 0)))
+                }
+                Ok(Child) => process::exit(if check() { 0 } else { 1 }),
+                Err(_) => panic!("Error forking tests :("),
+            }
+        }
+    }
+
+    fn standard_options(is_writable: &bool) -> FileOptions {
+        FileOptions::new()
+            .read(!*is_writable)
+            .write(*is_writable)
+            .create(*is_writable)
+    }
+
+    #[test]
+    fn lock_and_unlock() {
+        let filename = "filelock.test";
+
+        for already_exists in &[true, false] {
+            for already_locked in &[true, false] {
+                for already_writable in &[true, false] {
+                    for is_blocking in &[true, false] {
+                        for is_writable in &[true, false] {
+                            if !*already_exists && (*already_locked || *already_writable) {
+                                // nonsensical tests
+                                continue;
+                            }
+
+                            let _ = remove_file(filename).is_ok();
+
+                            let parent_lock = match *already_exists {
+                                false => None,
+                                true => {
+                                    OpenOptions::new()
+                                        .write(true)
+                                        .create(true)
+                                        .truncate(false)
+                                        .open(filename)
+                                        .expect("Test failed");
+
+                                    match *already_locked {
+                                        false => None,
+                                        true => {
+                                            let options = standard_options(already_writable);
+                                            match FileLock::lock(filename, true, options) {
+                                                Ok(lock) => Some(lock),
+                                                Err(err) => {
+                                                    panic!("Error creating parent lock ({})", err)
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            };
+
+                            unsafe {
+                                match fork() {
+                                    Ok(Parent { child: _ }) => {
+                                        sleep(Duration::from_millis(150));
+
+                                        if let Some(lock) = parent_lock {
+                                            lock.unlock().expect("Test failed");
+                                        }
+
+                                        sleep(Duration::from_millis(350));
+                                    }
+                                    Ok(Child) => {
+                                        let mut try_count = 0;
+                                        let mut locked = false;
+
+                                        match *already_locked {
+                                            true => match *is_blocking {
                                                 true => {
                                                     let options = standard_options(is_writable);
                                                     match FileLock::lock(filename, *is_blocking, options) {
@@ -242,22 +1108,21 @@ mod test {
                                                 }
                                                 }
                                                 false => {
-                                                    for _ in 0..5 {
-                                                        let options = standard_options(is_writable);
-                                                        match FileLock::lock(
-                                                            filename,
-                                                            *is_blocking,
-                                                            options,
-                                                        ) {
-                                                            Ok(_) => {
-                                                                locked = true;
-                                                                break;
-                                                            }
-                                                            Err(_) => {
-                                                                sleep(Duration::from_millis(50));
-                                                                try_count += 1;
-                                                            }
+                                                    let options = standard_options(is_writable);
+                                                    let policy = RetryPolicy::fixed(
+                                                        Duration::from_millis(50),
+                                                    )
+                                                    .max_attempts(5);
+                                                    match LockOptions::new()
+                                                        .retry(policy)
+                                                        .file_options(options)
+                                                        .lock(filename)
+                                                    {
+                                                        Ok(lock) => {
+                                                            locked = true;
+                                                            try_count = lock.attempts() - 1;
                                                         }
+                                                        Err(_) => try_count = 5,
                                                     }
                                                 }
                                             },
@@ -312,11 +1177,1473 @@ mod test {
                                 }
                             }
 
-                            let _ = remove_file(&filename).is_ok();
+                            let _ = remove_file(filename).is_ok();
                         }
                     }
                 }
             }
         }
     }
+
+    #[test]
+    fn lock_disjoint_ranges() {
+        let filename = "filelock_range.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().write(true).create(true);
+        let lock = FileLock::lock_range(filename, false, options, 0, 10).expect("Test failed");
+        assert_eq!(lock.range(), (0, 10));
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock_range(filename, false, options, 10, 10).is_ok()
+            }),
+            "Locking a disjoint range should succeed"
+        );
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock_range(filename, false, options, 5, 10).is_err()
+            }),
+            "Locking an overlapping range should fail"
+        );
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock(filename, false, options).is_err()
+            }),
+            "Locking the whole file should fail"
+        );
+
+        lock.unlock().expect("Test failed");
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock_range(filename, false, options, 5, 10).is_ok()
+            }),
+            "Locking a released range should succeed"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn ofd_locks_exclude_within_process() {
+        let filename = "filelock_ofd.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().write(true).create(true);
+        let lock =
+            FileLock::lock_with(filename, false, options, LockMode::Ofd).expect("Test failed");
+
+        let options = FileOptions::new().write(true);
+        assert!(
+            FileLock::lock_with(filename, false, options, LockMode::Ofd).is_err(),
+            "A second open file must not get the lock"
+        );
+
+        let other = std::thread::spawn(move || {
+            let options = FileOptions::new().write(true);
+            FileLock::lock_with(filename, false, options, LockMode::Ofd).is_err()
+        });
+        assert!(
+            other.join().unwrap(),
+            "Another thread must not get the lock"
+        );
+
+        lock.unlock().expect("Test failed");
+
+        let options = FileOptions::new().write(true);
+        assert!(
+            FileLock::lock_with(filename, false, options, LockMode::Ofd).is_ok(),
+            "Locking after unlock should succeed"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn flock_locks_whole_file_only() {
+        let filename = "filelock_flock.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().write(true).create(true);
+        let lock =
+            FileLock::lock_with(filename, false, options, LockMode::Flock).expect("Test failed");
+
+        let options = FileOptions::new().read(true);
+        assert!(
+            FileLock::lock_with(filename, false, options, LockMode::Flock).is_err(),
+            "A second open file must not get the lock"
+        );
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock_with(filename, false, options, LockMode::Flock).is_err()
+            }),
+            "Another process must not get the lock"
+        );
+
+        let options = FileOptions::new().write(true);
+        let err = FileLock::lock_range_with(filename, false, options, 0, 10, LockMode::Flock)
+            .expect_err("Test failed");
+        assert_eq!(err.operation(), Operation::Lock);
+        assert_eq!(std::io::Error::from(err).raw_os_error(), Some(libc::EINVAL));
+
+        lock.unlock().expect("Test failed");
+
+        let options = FileOptions::new().write(true);
+        assert!(
+            FileLock::lock_with(filename, false, options, LockMode::Flock).is_ok(),
+            "Locking after unlock should succeed"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn lockf_locks_exclusive_ranges() {
+        let filename = "filelock_lockf.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().write(true).create(true);
+        let lock = FileLock::lock_range_with(filename, false, options, 0, 10, LockMode::Lockf)
+            .expect("Test failed");
+
+        assert!(
+            in_child(|| {
+                use std::io::{Seek, SeekFrom};
+
+                let mut file = match OpenOptions::new().write(true).open(filename) {
+                    Ok(file) => file,
+                    Err(_) => return false,
+                };
+                if file.seek(SeekFrom::Start(7)).is_err() {
+                    return false;
+                }
+
+                match LockOptions::new()
+                    .blocking(false)
+                    .range(10, 10)
+                    .backend(LockMode::Lockf)
+                    .lock_file(file)
+                {
+                    Ok(mut lock) => lock.stream_position().ok() == Some(7),
+                    Err(_) => false,
+                }
+            }),
+            "Locking a disjoint range should succeed, leaving the file offset alone"
+        );
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().read(true).write(true);
+                FileLock::lock_range(filename, false, options, 5, 10).is_err()
+            }),
+            "lockf() and fcntl() locks should conflict"
+        );
+
+        lock.unlock().expect("Test failed");
+
+        let options = FileOptions::new().read(true).write(false);
+        let err =
+            FileLock::lock_with(filename, false, options, backend::Lockf).expect_err("Test failed");
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn lock_with_timeout() {
+        let filename = "filelock_timeout.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().write(true).create(true);
+        let lock = FileLock::lock_timeout(filename, Duration::from_millis(0), options)
+            .expect("Test failed");
+        assert!(lock.waited() < Duration::from_millis(100));
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                match FileLock::lock_timeout(filename, Duration::from_millis(200), options) {
+                    Err(LockError::TimedOut { waited, .. }) => waited >= Duration::from_millis(200),
+                    _ => false,
+                }
+            }),
+            "Locking should time out while the lock is held"
+        );
+
+        let unlocker = std::thread::spawn(move || {
+            sleep(Duration::from_millis(200));
+            drop(lock);
+        });
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                match FileLock::lock_timeout(filename, Duration::from_secs(5), options) {
+                    Ok(lock) => lock.waited() >= Duration::from_millis(100),
+                    Err(_) => false,
+                }
+            }),
+            "Locking should succeed once the lock is released"
+        );
+
+        unlocker.join().unwrap();
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn upgrade_and_downgrade() {
+        let filename = "filelock_upgrade.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().read(true).write(true).create(true);
+        let mut lock = FileLock::lock(filename, false, options).expect("Test failed");
+        assert_eq!(lock.kind(), LockKind::Exclusive);
+
+        lock.downgrade().expect("Test failed");
+        assert_eq!(lock.kind(), LockKind::Shared);
+
+        let reader = unsafe {
+            match fork() {
+                Ok(Parent { child }) => child,
+                Ok(Child) => {
+                    let options = FileOptions::new().read(true).write(false);
+                    let lock = FileLock::lock(filename, false, options);
+                    sleep(Duration::from_millis(300));
+                    process::exit(if lock.is_ok() { 0 } else { 1 });
+                }
+                Err(_) => panic!("Error forking tests :("),
+            }
+        };
+
+        sleep(Duration::from_millis(100));
+        assert!(
+            lock.upgrade(false).is_err(),
+            "Upgrading should fail while another reader holds the lock"
+        );
+        assert_eq!(lock.kind(), LockKind::Shared);
+        assert!(lock.is_held());
+
+        lock.upgrade_timeout(Duration::from_secs(5))
+            .expect("Test failed");
+        assert_eq!(lock.kind(), LockKind::Exclusive);
+        assert!(matches!(
+            waitpid(reader, None),
+            Ok(WaitStatus::Exited(_, 0))
+        ));
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().read(true).write(false);
+                FileLock::lock(filename, false, options).is_err()
+            }),
+            "Reading should fail after upgrading"
+        );
+
+        lock.downgrade().expect("Test failed");
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().read(true).write(false);
+                FileLock::lock(filename, false, options).is_ok()
+            }),
+            "Reading should succeed after downgrading"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn query_conflicting_lock() {
+        let filename = "filelock_query.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().write(true).create(true);
+        let lock = FileLock::lock_range(filename, false, options, 10, 20).expect("Test failed");
+        let pid = process::id();
+
+        assert!(
+            in_child(|| {
+                let holder =
+                    FileLock::query(filename, LockKind::Shared, 0, 0).expect("Test failed");
+                holder
+                    == Some(LockInfo {
+                        kind: LockKind::Exclusive,
+                        start: 10,
+                        len: 20,
+                        pid: Some(pid),
+                    })
+            }),
+            "The parent's lock should be reported"
+        );
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                match FileLock::lock_range(filename, false, options, 0, 15) {
+                    Err(err @ LockError::WouldBlock { .. }) => {
+                        let holder = match err {
+                            LockError::WouldBlock { holder, .. } => holder,
+                            _ => None,
+                        };
+                        err.path() == Some(Path::new(filename))
+                            && holder.and_then(|holder| holder.pid) == Some(pid)
+                            && err.to_string()
+                                == format!("failed to lock {}: locked by pid {}", filename, pid)
+                    }
+                    _ => false,
+                }
+            }),
+            "The would-block error should name the holder"
+        );
+
+        assert!(
+            in_child(|| FileLock::query(filename, LockKind::Exclusive, 0, 10)
+                .expect("Test failed")
+                .is_none()),
+            "Disjoint ranges should not conflict"
+        );
+
+        lock.unlock().expect("Test failed");
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn lock_open_files() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+        use std::os::unix::io::OwnedFd;
+
+        let filename = "filelock_open.test";
+        let _ = remove_file(filename).is_ok();
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(filename)
+            .expect("Test failed");
+        let lock = FileLock::lock_file_with(
+            OwnedFd::from(file),
+            false,
+            LockKind::Exclusive,
+            LockMode::Ofd,
+        )
+        .expect("Test failed");
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(filename)
+            .expect("Test failed");
+        assert!(
+            BorrowedFileLock::lock_with(&file, false, LockKind::Shared, LockMode::Ofd).is_err(),
+            "Borrowing a locked file should fail"
+        );
+
+        lock.unlock().expect("Test failed");
+
+        {
+            let borrowed =
+                BorrowedFileLock::lock_with(&file, false, LockKind::Exclusive, LockMode::Ofd)
+                    .expect("Test failed");
+            assert_eq!(borrowed.kind(), LockKind::Exclusive);
+            (&file).write_all(b"locked").expect("Test failed");
+
+            let options = FileOptions::new().read(true).write(false);
+            assert!(
+                FileLock::lock_with(filename, false, options, LockMode::Ofd).is_err(),
+                "Locking a borrowed lock's file should fail"
+            );
+        }
+
+        let mut contents = String::new();
+        file.seek(SeekFrom::Start(0)).expect("Test failed");
+        file.read_to_string(&mut contents).expect("Test failed");
+        assert_eq!(contents, "locked");
+
+        let options = FileOptions::new().read(true).write(false);
+        assert!(
+            FileLock::lock_with(filename, false, options, LockMode::Ofd).is_ok(),
+            "Dropping the borrowed lock should unlock the file"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn cancel_waiting_for_lock() {
+        let filename = "filelock_cancel.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().write(true).create(true);
+        let lock = FileLock::lock(filename, false, options).expect("Test failed");
+
+        assert!(
+            in_child(|| {
+                let token = CancelToken::new();
+                let canceller = token.clone();
+                std::thread::spawn(move || {
+                    sleep(Duration::from_millis(100));
+                    canceller.cancel();
+                });
+
+                let options = FileOptions::new().write(true);
+                match FileLock::lock_cancellable(filename, &token, options) {
+                    Err(LockError::Cancelled { waited, .. }) => {
+                        waited >= Duration::from_millis(100)
+                    }
+                    _ => false,
+                }
+            }),
+            "Waiting should end once cancelled"
+        );
+
+        assert!(
+            in_child(|| {
+                let token = CancelToken::new();
+                token.cancel();
+
+                let options = FileOptions::new().write(true);
+                let cancelled = FileLock::lock_cancellable(filename, &token, options);
+                matches!(cancelled, Err(LockError::Cancelled { .. }))
+            }),
+            "Waiting should not even start once cancelled"
+        );
+
+        drop(lock);
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock_cancellable(filename, &CancelToken::new(), options).is_ok()
+            }),
+            "Locking should succeed without competition"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn lock_with_tokio() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::runtime::Builder;
+
+        let filename = "filelock_tokio.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().write(true).create(true);
+        let lock = FileLock::lock(filename, false, options).expect("Test failed");
+
+        assert!(
+            in_child(|| {
+                let runtime = Builder::new_current_thread().build().unwrap();
+                let options = FileOptions::new().write(true);
+                let timeout = Duration::from_millis(100);
+                let locked =
+                    runtime.block_on(AsyncFileLock::lock_timeout(filename, timeout, options));
+                matches!(locked, Err(LockError::TimedOut { .. }))
+            }),
+            "Waiting should end after the timeout"
+        );
+
+        drop(lock);
+
+        assert!(
+            in_child(|| {
+                let runtime = Builder::new_current_thread().build().unwrap();
+                let options = FileOptions::new().write(true).truncate(true);
+                let mut lock = match runtime.block_on(AsyncFileLock::lock(filename, true, options))
+                {
+                    Ok(lock) => lock,
+                    Err(_) => return false,
+                };
+                runtime.block_on(lock.write_all(b"Hello")).is_ok()
+                    && runtime.block_on(lock.unlock()).is_ok()
+            }),
+            "Locking should succeed without competition"
+        );
+
+        let runtime = Builder::new_current_thread().build().unwrap();
+        let options = FileOptions::new().read(true).write(false);
+        let mut lock = runtime
+            .block_on(AsyncFileLock::lock(filename, false, options))
+            .expect("Test failed");
+        let mut contents = String::new();
+        runtime
+            .block_on(lock.read_to_string(&mut contents))
+            .expect("Test failed");
+        assert_eq!(contents, "Hello");
+        assert_eq!(lock.kind(), LockKind::Shared);
+
+        drop(lock);
+
+        let options = FileOptions::new().append(true);
+        let mut lock = runtime
+            .block_on(AsyncFileLock::lock(filename, false, options))
+            .expect("Test failed");
+        runtime
+            .block_on(lock.write_all(b", World"))
+            .expect("Test failed");
+        // the write is still in flight
+        drop(lock);
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().read(true).write(false);
+                let mut lock = match FileLock::lock(filename, true, options) {
+                    Ok(lock) => lock,
+                    Err(_) => return false,
+                };
+                let mut contents = String::new();
+                lock.read_to_string(&mut contents).is_ok() && contents == "Hello, World"
+            }),
+            "Dropping the lock should wait for the write to land"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn lock_with_any_executor() {
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake};
+        use std::thread::{self, Thread};
+
+        struct Unpark(Thread);
+
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark();
+            }
+        }
+
+        /// The simplest executor there is
+        fn block_on<F: Future + Unpin>(mut future: F) -> F::Output {
+            let waker = Arc::new(Unpark(thread::current())).into();
+            let mut cx = Context::from_waker(&waker);
+
+            loop {
+                match Pin::new(&mut future).poll(&mut cx) {
+                    Poll::Ready(output) => return output,
+                    Poll::Pending => thread::park(),
+                }
+            }
+        }
+
+        let filename = "filelock_async.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().write(true).create(true);
+        let lock = FileLock::lock(filename, false, options).expect("Test failed");
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                let timeout = Duration::from_millis(100);
+                let locked = block_on(FileLock::lock_timeout_async(filename, timeout, options));
+                matches!(locked, Err(LockError::TimedOut { .. }))
+            }),
+            "Waiting should end after the timeout"
+        );
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                let locked = block_on(FileLock::lock_async(filename, false, options));
+                matches!(locked, Err(LockError::WouldBlock { .. }))
+            }),
+            "Locking should fail right away without blocking"
+        );
+
+        let unlocker = thread::spawn(move || {
+            sleep(Duration::from_millis(100));
+            drop(lock);
+        });
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                block_on(FileLock::lock_async(filename, true, options)).is_ok()
+            }),
+            "Waiting should end once the lock is released"
+        );
+        unlocker.join().expect("Test failed");
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn wait_for_lock_in_event_loop() {
+        use nix::poll::{poll, PollFd, PollFlags};
+        use std::os::unix::io::AsRawFd;
+        use std::thread;
+
+        let filename = "filelock_waiter.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().write(true).create(true);
+        let lock = FileLock::lock(filename, false, options).expect("Test failed");
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                let mut waiter = LockWaiter::lock(filename, options).unwrap();
+                let mut fds = [PollFd::new(waiter.as_raw_fd(), PollFlags::POLLIN)];
+
+                let is_ready = poll(&mut fds, 100) != Ok(0) || waiter.take().is_some();
+                waiter.cancel();
+                let is_cancelled = poll(&mut fds, 1000) == Ok(1)
+                    && matches!(waiter.take(), Some(Err(LockError::Cancelled { .. })));
+
+                !is_ready && is_cancelled
+            }),
+            "Waiting should go on until cancelled"
+        );
+
+        assert!(
+            in_child(|| {
+                let threads =
+                    || std::fs::read_dir("/proc/self/task").map_or(0, |tasks| tasks.count());
+                let before = threads();
+                let waiters: Vec<_> = (0..10)
+                    .filter_map(|_| LockWaiter::lock(filename, FileOptions::new().write(true)).ok())
+                    .collect();
+
+                waiters.len() == 10 && threads() == before + 1
+            }),
+            "All waiters should share a single thread"
+        );
+
+        let unlocker = thread::spawn(move || {
+            sleep(Duration::from_millis(100));
+            drop(lock);
+        });
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                let mut waiter = LockWaiter::lock(filename, options).unwrap();
+                let mut fds = [PollFd::new(waiter.as_raw_fd(), PollFlags::POLLIN)];
+
+                poll(&mut fds, 5000) == Ok(1) && matches!(waiter.take(), Some(Ok(_)))
+            }),
+            "The descriptor should become readable once the lock is ours"
+        );
+        unlocker.join().expect("Test failed");
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn guards_hand_back_the_file() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+
+        let filename = "filelock_guard.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().write(true).create(true);
+        let mut guard = FileLock::lock_exclusive(filename, false, options).expect("Test failed");
+        guard.write_all(b"Hello").expect("Test failed");
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().read(true).write(false);
+                FileLock::lock_shared(filename, false, options).is_err()
+            }),
+            "An exclusive guard should keep out readers"
+        );
+
+        let mut file = guard.unlock().expect("Test failed");
+        file.write_all(b", World!").expect("Test failed");
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock_exclusive(filename, false, options).is_ok()
+            }),
+            "Unlocking should release the lock, but not close the file"
+        );
+
+        let options = FileOptions::new().read(true).write(false);
+        let mut guard = FileLock::lock_shared(filename, false, options).expect("Test failed");
+        let mut contents = String::new();
+        guard.read_to_string(&mut contents).expect("Test failed");
+        assert_eq!(contents, "Hello, World!");
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock_exclusive(filename, false, options).is_err()
+            }),
+            "A shared guard should keep out writers"
+        );
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().read(true).write(true);
+                match FileLock::lock_shared(filename, false, options) {
+                    Ok(shared) => matches!(
+                        shared.upgrade(false),
+                        Err(ConvertError::Kept(
+                            _,
+                            LockError::WouldBlock {
+                                operation: Operation::Upgrade,
+                                ..
+                            },
+                        ))
+                    ),
+                    Err(_) => false,
+                }
+            }),
+            "Upgrading should fail while another reader holds the file"
+        );
+
+        let mut file = guard.into_inner();
+        file.seek(SeekFrom::Start(0)).expect("Test failed");
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock_exclusive(filename, false, options).is_ok()
+            }),
+            "Turning a guard into its file should release the lock"
+        );
+
+        let options = FileOptions::new().read(true).write(true);
+        let shared = FileLock::lock_shared(filename, false, options).expect("Test failed");
+        let exclusive = shared.upgrade(false).expect("Test failed");
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().read(true).write(false);
+                FileLock::lock_shared(filename, false, options).is_err()
+            }),
+            "An upgraded guard should keep out readers"
+        );
+
+        let _shared = exclusive.downgrade().expect("Test failed");
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().read(true).write(false);
+                FileLock::lock_shared(filename, false, options).is_ok()
+            }),
+            "A downgraded guard should let readers in"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn lock_kind_is_explicit() {
+        let filename = "filelock_kind.test";
+        let _ = remove_file(filename).is_ok();
+
+        let options = FileOptions::new().read(true).write(true).create(true);
+        let lock = FileLock::lock(filename, false, options).expect("Test failed");
+        assert_eq!(lock.kind(), LockKind::Exclusive);
+        drop(lock);
+
+        let options = FileOptions::new().write(true).read(true);
+        let lock = FileLock::lock(filename, false, options).expect("Test failed");
+        assert_eq!(
+            lock.kind(),
+            LockKind::Exclusive,
+            "Opening for reading should not decide the lock kind"
+        );
+        drop(lock);
+
+        let options = FileOptions::new()
+            .read(true)
+            .write(true)
+            .lock_kind(LockKind::Shared);
+        let mut lock = FileLock::lock(filename, false, options).expect("Test failed");
+        assert_eq!(lock.kind(), LockKind::Shared);
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().read(true);
+                FileLock::lock(filename, false, options).is_ok()
+            }),
+            "An explicitly shared lock should let readers in"
+        );
+        lock.upgrade(false).expect("Test failed");
+        drop(lock);
+
+        let options = FileOptions::new().write(true).lock_kind(LockKind::Shared);
+        match FileLock::lock(filename, false, options) {
+            Err(err @ LockError::AccessMode { .. }) => assert_eq!(
+                err.to_string(),
+                format!(
+                    "failed to lock {}: a shared lock needs the file to be open for reading",
+                    filename
+                )
+            ),
+            other => panic!("Expected an access mode error, got {:?}", other),
+        }
+
+        std::fs::write(filename, b"Hello, World!").expect("Test failed");
+        let options = FileOptions::new()
+            .write(true)
+            .truncate(true)
+            .lock_kind(LockKind::Shared);
+        assert!(matches!(
+            FileLock::lock(filename, false, options),
+            Err(LockError::AccessMode { .. })
+        ));
+        assert_eq!(
+            std::fs::read(filename).expect("Test failed"),
+            b"Hello, World!",
+            "A rejected lock must not truncate the file"
+        );
+
+        let created = "filelock_kind_created.test";
+        let _ = remove_file(created).is_ok();
+        let options = FileOptions::new()
+            .write(true)
+            .create(true)
+            .lock_kind(LockKind::Shared);
+        assert!(matches!(
+            FileLock::lock(created, false, options),
+            Err(LockError::AccessMode { .. })
+        ));
+        assert!(
+            !Path::new(created).exists(),
+            "A rejected lock must not create the file"
+        );
+
+        let options = FileOptions::new().read(true);
+        let mut lock = FileLock::lock(filename, false, options).expect("Test failed");
+        assert_eq!(lock.kind(), LockKind::Shared);
+        assert!(matches!(
+            lock.upgrade(false),
+            Err(LockError::AccessMode {
+                operation: Operation::Upgrade,
+                kind: LockKind::Exclusive,
+                ..
+            })
+        ));
+
+        let options = FileOptions::new().read(true).lock_kind(LockKind::Exclusive);
+        assert!(
+            FileLock::lock_with(filename, false, options, LockMode::Flock).is_ok(),
+            "flock() should not care about the access mode"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn file_options_match_open_options() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let filename = "filelock_open_options.test";
+        let linkname = "filelock_open_options_link.test";
+        let _ = remove_file(filename).is_ok();
+        let _ = remove_file(linkname).is_ok();
+
+        let options = FileOptions::new().write(true).create(true).mode(0o600);
+        let lock = FileLock::lock(filename, false, options).expect("Test failed");
+        let mode = lock.metadata().expect("Test failed").permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        drop(lock);
+
+        symlink(filename, linkname).expect("Test failed");
+        let options = FileOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NOFOLLOW);
+        match FileLock::lock(linkname, false, options) {
+            Err(LockError::Open { source, .. }) => {
+                assert_eq!(source.raw_os_error(), Some(libc::ELOOP))
+            }
+            other => panic!("Expected an open error, got {:?}", other),
+        }
+
+        let mut open_options = OpenOptions::new();
+        open_options.read(true);
+        let lock = FileLock::lock(filename, false, open_options.into()).expect("Test failed");
+        assert_eq!(lock.kind(), LockKind::Shared);
+        drop(lock);
+
+        let mut open_options = OpenOptions::new();
+        open_options.append(true);
+        let lock = FileLock::lock(filename, false, open_options.into()).expect("Test failed");
+        assert_eq!(lock.kind(), LockKind::Exclusive);
+        drop(lock);
+
+        let _ = remove_file(linkname).is_ok();
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn lock_with_options() {
+        let filename = "filelock_options.test";
+        let _ = remove_file(filename).is_ok();
+
+        let lock = LockOptions::new().lock(filename).expect("Test failed");
+        assert_eq!(lock.kind(), LockKind::Exclusive);
+        assert_eq!(lock.range(), (0, 0));
+        drop(lock);
+
+        let lock = LockOptions::new()
+            .shared()
+            .blocking(false)
+            .range(10, 20)
+            .lock(filename)
+            .expect("Test failed");
+        assert_eq!(lock.kind(), LockKind::Shared);
+        assert_eq!(lock.range(), (10, 20));
+
+        assert!(
+            in_child(|| {
+                let locked = LockOptions::new()
+                    .timeout(Duration::from_millis(50))
+                    .cancellable(&CancelToken::new())
+                    .range(0, 15)
+                    .lock(filename);
+                matches!(locked, Err(LockError::TimedOut { .. }))
+            }),
+            "Options should combine a timeout with cancellation"
+        );
+
+        assert!(
+            in_child(|| LockOptions::new()
+                .blocking(false)
+                .range(0, 10)
+                .lock(filename)
+                .is_ok()),
+            "Locking a disjoint range should succeed"
+        );
+        drop(lock);
+
+        let options = FileOptions::new().write(true);
+        let lock = LockOptions::new()
+            .backend(LockMode::Ofd)
+            .file_options(options)
+            .lock(filename)
+            .expect("Test failed");
+        assert!(
+            LockOptions::new()
+                .blocking(false)
+                .backend(LockMode::Ofd)
+                .lock(filename)
+                .is_err(),
+            "Options should pass on the backend"
+        );
+        drop(lock);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .open(filename)
+            .expect("Test failed");
+        let lock = LockOptions::new().lock_file(file).expect("Test failed");
+        assert_eq!(lock.kind(), LockKind::Shared);
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn retry_with_policy() {
+        let filename = "filelock_retry.test";
+        let _ = remove_file(filename).is_ok();
+
+        let lock = LockOptions::new().lock(filename).expect("Test failed");
+
+        assert!(
+            in_child(|| {
+                let policy = RetryPolicy::fixed(Duration::from_millis(10)).max_attempts(3);
+                match LockOptions::new().retry(policy).lock(filename) {
+                    Err(LockError::RetriesExhausted {
+                        attempts, waited, ..
+                    }) => attempts == 3 && waited >= Duration::from_millis(20),
+                    _ => false,
+                }
+            }),
+            "Retrying should stop after the maximum number of attempts"
+        );
+
+        assert!(
+            in_child(|| {
+                let policy =
+                    RetryPolicy::exponential(Duration::from_millis(5), Duration::from_millis(20))
+                        .jitter(true)
+                        .deadline(Duration::from_millis(100));
+                match LockOptions::new().retry(policy).lock(filename) {
+                    Err(LockError::TimedOut { waited, .. }) => waited >= Duration::from_millis(100),
+                    _ => false,
+                }
+            }),
+            "Retrying should stop at the deadline"
+        );
+
+        let unlocker = std::thread::spawn(move || {
+            sleep(Duration::from_millis(100));
+            drop(lock);
+        });
+
+        assert!(
+            in_child(|| {
+                let policy = RetryPolicy::fixed(Duration::from_millis(20));
+                match LockOptions::new().retry(policy).lock(filename) {
+                    Ok(lock) => lock.attempts() > 1 && lock.waited() >= Duration::from_millis(20),
+                    Err(_) => false,
+                }
+            }),
+            "Retrying should go on until the lock is ours"
+        );
+        unlocker.join().expect("Test failed");
+
+        let policy = RetryPolicy::exponential(Duration::from_millis(10), Duration::from_millis(40));
+        assert_eq!(policy.pause(1), Duration::from_millis(10));
+        assert_eq!(policy.pause(2), Duration::from_millis(20));
+        assert_eq!(policy.pause(5), Duration::from_millis(40));
+        assert_eq!(policy.pause(1000), Duration::from_millis(40));
+
+        let policy = policy.jitter(true);
+        assert!((1..10).all(|attempt| {
+            let pause = policy.pause(attempt);
+            pause >= Duration::from_millis(5) && pause <= Duration::from_millis(40)
+        }));
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn blocking_lock_survives_signals() {
+        use nix::sys::pthread::{pthread_kill, pthread_self};
+        use nix::sys::signal::Signal::SIGUSR1;
+        use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet};
+
+        extern "C" fn ignore(_: libc::c_int) {}
+
+        /// Interrupts the calling thread with a signal which doesn't restart system calls
+        fn interrupt_soon() {
+            let handler = SigAction::new(
+                SigHandler::Handler(ignore),
+                SaFlags::empty(),
+                SigSet::empty(),
+            );
+            unsafe { sigaction(SIGUSR1, &handler) }.expect("Test failed");
+
+            let waiter = pthread_self();
+            std::thread::spawn(move || {
+                sleep(Duration::from_millis(50));
+                pthread_kill(waiter, SIGUSR1).expect("Test failed");
+            });
+        }
+
+        let filename = "filelock_signals.test";
+        let _ = remove_file(filename).is_ok();
+
+        let lock = LockOptions::new().lock(filename).expect("Test failed");
+
+        assert!(
+            in_child(|| {
+                interrupt_soon();
+                let locked = LockOptions::new().interruptible(true).lock(filename);
+                matches!(locked, Err(LockError::Interrupted { .. }))
+            }),
+            "Waiting should end when interrupted if asked to"
+        );
+
+        let unlocker = std::thread::spawn(move || {
+            sleep(Duration::from_millis(200));
+            drop(lock);
+        });
+
+        assert!(
+            in_child(|| {
+                interrupt_soon();
+                match LockOptions::new().lock(filename) {
+                    Ok(lock) => lock.waited() >= Duration::from_millis(100),
+                    Err(_) => false,
+                }
+            }),
+            "Waiting should carry on after being interrupted"
+        );
+        unlocker.join().expect("Test failed");
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn posix_locks_exclude_other_threads() {
+        let filename = "filelock_threads.test";
+        let _ = remove_file(filename).is_ok();
+
+        let lock = LockOptions::new()
+            .range(0, 10)
+            .lock(filename)
+            .expect("Test failed");
+        let pid = process::id();
+
+        let other = std::thread::spawn(move || {
+            let blocked = match LockOptions::new().blocking(false).lock(filename) {
+                Err(LockError::WouldBlock { holder, .. }) => {
+                    holder.and_then(|holder| holder.pid) == Some(pid)
+                }
+                _ => false,
+            };
+            let disjoint = LockOptions::new().shared().range(20, 10).lock(filename);
+
+            blocked && disjoint.is_ok()
+        });
+        assert!(
+            other.join().unwrap(),
+            "Another thread must only get a lock on another range"
+        );
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let waiter = std::thread::spawn(move || {
+            let locked = LockOptions::new().lock(filename);
+            sender.send(locked.is_ok()).expect("Test failed");
+        });
+        assert!(
+            receiver.recv_timeout(Duration::from_millis(100)).is_err(),
+            "A blocking lock from another thread should wait"
+        );
+
+        drop(lock);
+        assert_eq!(
+            receiver.recv_timeout(Duration::from_secs(5)),
+            Ok(true),
+            "A blocking lock from another thread should get the lock once released"
+        );
+        waiter.join().expect("Test failed");
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn upgrading_threads_detect_deadlock() {
+        let filename = "filelock_threads_deadlock.test";
+        let _ = remove_file(filename).is_ok();
+        File::create(filename).expect("Test failed");
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let threads: Vec<_> = (0..2)
+            .map(|index| {
+                let sender = sender.clone();
+
+                std::thread::spawn(move || {
+                    let options = FileOptions::new().read(true).write(true);
+                    let mut lock = LockOptions::new()
+                        .shared()
+                        .file_options(options)
+                        .lock(filename)
+                        .expect("Test failed");
+
+                    // the first one is waiting by the time the second one tries
+                    sleep(Duration::from_millis(100 + index * 200));
+                    let upgraded = lock.upgrade(true);
+                    sender.send(upgraded).expect("Test failed");
+                })
+            })
+            .collect();
+
+        let first = receiver.recv_timeout(Duration::from_secs(5));
+        let second = receiver.recv_timeout(Duration::from_secs(5));
+        assert!(
+            matches!(first, Ok(Err(LockError::Deadlock { .. }))),
+            "Upgrading while the other thread waits to upgrade should be a deadlock, got {:?}",
+            first
+        );
+        assert!(
+            matches!(second, Ok(Ok(()))),
+            "The other thread should get its upgrade once the first one gives up, got {:?}",
+            second
+        );
+        for thread in threads {
+            thread.join().expect("Test failed");
+        }
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn downgrade_lets_other_threads_in() {
+        let filename = "filelock_threads_downgrade.test";
+        let _ = remove_file(filename).is_ok();
+
+        let mut lock = LockOptions::new().lock(filename).expect("Test failed");
+
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let waiter = std::thread::spawn(move || {
+            let locked = LockOptions::new().shared().lock(filename);
+            sender.send(locked.is_ok()).expect("Test failed");
+        });
+        assert!(
+            receiver.recv_timeout(Duration::from_millis(100)).is_err(),
+            "A shared lock from another thread should wait for the exclusive one"
+        );
+
+        lock.downgrade().expect("Test failed");
+        let woken = receiver.recv_timeout(Duration::from_secs(2));
+
+        drop(lock);
+        waiter.join().expect("Test failed");
+        assert_eq!(
+            woken,
+            Ok(true),
+            "Downgrading should let a waiting reader in right away"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn dropping_a_lock_keeps_the_others() {
+        let filename = "filelock_independent.test";
+        let _ = remove_file(filename).is_ok();
+        File::create(filename).expect("Test failed");
+
+        let first = LockOptions::new()
+            .shared()
+            .range(0, 20)
+            .lock(filename)
+            .expect("Test failed");
+        let second = LockOptions::new()
+            .shared()
+            .range(10, 20)
+            .lock(filename)
+            .expect("Test failed");
+        drop(second);
+
+        let other =
+            std::thread::spawn(move || LockOptions::new().blocking(false).lock(filename).is_err());
+        assert!(
+            other.join().unwrap(),
+            "Another thread must not get the lock"
+        );
+
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock_range(filename, false, options, 15, 1).is_err()
+            }),
+            "The first lock should survive the others being dropped"
+        );
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock_range(filename, false, options, 20, 10).is_ok()
+            }),
+            "Only the range no other lock holds should be unlocked"
+        );
+
+        drop(first);
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock(filename, false, options).is_ok()
+            }),
+            "Locking after all locks are dropped should succeed"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn descriptors_are_reused_while_locked() {
+        let filename = "filelock_descriptors.test";
+        let _ = remove_file(filename).is_ok();
+        File::create(filename).expect("Test failed");
+
+        // counted in a child of our own, which no other test opens files in
+        assert!(
+            in_child(|| {
+                let open = || std::fs::read_dir("/proc/self/fd").map_or(0, |fds| fds.count());
+                let lock_and_query = || {
+                    let options = FileOptions::new().read(true);
+                    FileLock::lock_range(filename, false, options, 10, 10).is_ok()
+                        && FileLock::query(filename, LockKind::Exclusive, 0, 0).is_ok()
+                };
+
+                let options = FileOptions::new().read(true);
+                let _held = match FileLock::lock(filename, false, options) {
+                    Ok(lock) => lock,
+                    Err(_) => return false,
+                };
+                if !lock_and_query() {
+                    return false;
+                }
+
+                let before = open();
+                (0..100).all(|_| lock_and_query()) && open() == before
+            }),
+            "Locking the same file over and over must not leave descriptors behind"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn lock_through_sidecar() {
+        use std::io::Write;
+        use std::os::unix::fs::PermissionsExt;
+
+        let filename = "filelock_sidecar.test";
+        let sidecar = "filelock_sidecar.test.lock";
+        let _ = remove_file(filename).is_ok();
+        let _ = remove_file(sidecar).is_ok();
+
+        let (guard, mut file) = LockOptions::new()
+            .lock_sidecar(filename)
+            .expect("Test failed");
+        assert_eq!(guard.path(), Path::new(sidecar));
+        assert!(file.write_all(b"Hello, World!").is_ok());
+
+        let mode = std::fs::metadata(sidecar)
+            .expect("Test failed")
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        assert!(
+            in_child(|| {
+                LockOptions::new()
+                    .blocking(false)
+                    .lock_sidecar(filename)
+                    .is_err()
+            }),
+            "Another process must not get the sidecar"
+        );
+        assert!(
+            in_child(|| {
+                let options = FileOptions::new().write(true);
+                FileLock::lock(filename, false, options).is_ok()
+            }),
+            "The file itself should not be locked"
+        );
+
+        guard.unlock().expect("Test failed");
+        assert!(Path::new(sidecar).exists(), "The sidecar should be kept");
+        let _ = remove_file(sidecar).is_ok();
+
+        let sidecar = Sidecar::new().suffix(".lck").cleanup(true);
+        let options = LockOptions::new().shared().sidecar(sidecar.clone());
+        let (guard, _) = options.lock_sidecar(filename).expect("Test failed");
+        assert!(
+            in_child(|| {
+                let options = LockOptions::new().shared().blocking(false);
+                let locked = options.sidecar(sidecar.clone()).lock_sidecar(filename);
+                locked.is_ok() && {
+                    drop(locked);
+                    Path::new("filelock_sidecar.test.lck").exists()
+                }
+            }),
+            "Another reader must not remove the sidecar while we hold it"
+        );
+        drop(guard);
+        assert!(
+            Path::new("filelock_sidecar.test.lck").exists(),
+            "Shared locks should not clean up the sidecar"
+        );
+
+        let options = LockOptions::new().sidecar(sidecar);
+        let (guard, _) = options.lock_sidecar(filename).expect("Test failed");
+        assert_eq!(guard.path(), Path::new("filelock_sidecar.test.lck"));
+
+        drop(guard);
+        assert!(
+            !Path::new("filelock_sidecar.test.lck").exists(),
+            "The sidecar should be cleaned up"
+        );
+
+        let _ = remove_file(filename).is_ok();
+    }
+
+    #[test]
+    fn replace_whole_file() {
+        use std::io::{Error, Write};
+        use std::panic;
+
+        let dir = "filelock_replace.test.d";
+        let filename = "filelock_replace.test.d/data";
+        let _ = std::fs::remove_dir_all(dir).is_ok();
+        std::fs::create_dir(dir).expect("Test failed");
+        std::fs::write(filename, b"old").expect("Test failed");
+
+        let written = FileLock::replace(filename, |file| file.write_all(b"new").map(|_| 3));
+        assert_eq!(written.expect("Test failed"), 3);
+        assert_eq!(std::fs::read(filename).expect("Test failed"), b"new");
+
+        let failed = FileLock::replace(filename, |file| {
+            file.write_all(b"torn")?;
+            Err::<(), _>(Error::other("writer failed"))
+        });
+        assert!(matches!(
+            failed,
+            Err(LockError::Io {
+                operation: Operation::Replace,
+                ..
+            })
+        ));
+
+        let panicked = panic::catch_unwind(|| {
+            FileLock::replace(filename, |file| -> std::io::Result<()> {
+                file.write_all(b"torn")?;
+                panic!("writer panicked")
+            })
+        });
+        assert!(panicked.is_err());
+
+        assert_eq!(
+            std::fs::read(filename).expect("Test failed"),
+            b"new",
+            "The file should be left alone when writing fails"
+        );
+        let mut left = std::fs::read_dir(dir)
+            .expect("Test failed")
+            .map(|entry| entry.expect("Test failed").file_name())
+            .collect::<Vec<_>>();
+        left.sort();
+        assert_eq!(
+            left,
+            vec!["data", "data.lock"],
+            "Temporary files should be removed"
+        );
+
+        let _ = std::fs::remove_dir_all(dir).is_ok();
+    }
+
+    #[test]
+    fn verify_lock_is_on_current_file() {
+        use std::os::unix::fs::MetadataExt;
+
+        let filename = "filelock_verify.test";
+        let _ = remove_file(filename).is_ok();
+
+        let lock = LockOptions::new().lock(filename).expect("Test failed");
+
+        let remover = std::thread::spawn(move || {
+            sleep(Duration::from_millis(200));
+            remove_file(filename).expect("Test failed");
+            drop(lock);
+        });
+
+        assert!(
+            in_child(|| {
+                let lock = match LockOptions::new().verify(true).lock(filename) {
+                    Ok(lock) => lock,
+                    Err(_) => return false,
+                };
+                let at_path = std::fs::metadata(filename).map(|metadata| metadata.ino());
+                let locked = lock.metadata().map(|metadata| metadata.ino());
+
+                at_path.ok() == locked.ok() && lock.waited() >= Duration::from_millis(100)
+            }),
+            "The lock should end up on the file now at the path"
+        );
+        remover.join().expect("Test failed");
+
+        let _ = remove_file(filename).is_ok();
+    }
 }
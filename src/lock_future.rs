@@ -0,0 +1,106 @@
+use acquisition::Acquisition;
+use error::LockError;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use FileLock;
+
+/// What the helper thread and the future share
+#[derive(Debug, Default)]
+struct Shared {
+    result: Option<Result<FileLock, LockError>>,
+    waker: Option<Waker>,
+    is_dropped: bool,
+}
+
+/// A future resolving to a [`FileLock`](struct.FileLock.html), usable with any executor
+///
+/// Waiting for the lock happens on a helper thread, which wakes the task
+/// once it is done, so no particular runtime is needed. Nothing happens until
+/// the future is first polled. Dropping the future before it completes gives
+/// up waiting, and a lock taken out in the meantime is released again.
+///
+/// Only available with the `async` feature.
+///
+/// # Examples
+///
+///```
+///extern crate file_lock;
+///
+///use file_lock::{FileLock, FileOptions, LockFuture};
+///
+///fn lock_log() -> LockFuture {
+///    let options = FileOptions::new().write(true).create(true).append(true);
+///
+///    // hand this to smol, futures::executor or whatever runs your tasks
+///    FileLock::lock_async("myfile.txt", true, options)
+///}
+///#
+///# fn main() {
+///#     drop(lock_log());
+///# }
+///```
+#[derive(Debug)]
+pub struct LockFuture {
+    acquisition: Acquisition,
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl LockFuture {
+    pub(crate) fn new(acquisition: Acquisition) -> Self {
+        LockFuture {
+            acquisition,
+            shared: Arc::default(),
+        }
+    }
+}
+
+impl Future for LockFuture {
+    type Output = Result<FileLock, LockError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut shared = this.shared.lock().unwrap_or_else(|err| err.into_inner());
+
+        if let Some(result) = shared.result.take() {
+            return Poll::Ready(result);
+        }
+        shared.waker = Some(cx.waker().clone());
+        drop(shared);
+
+        if let Some(acquire) = this.acquisition.start() {
+            let shared = this.shared.clone();
+
+            thread::spawn(move || {
+                let result = acquire();
+                let mut shared = shared.lock().unwrap_or_else(|err| err.into_inner());
+
+                // nobody is left to unlock the file, so drop it here and now
+                if shared.is_dropped {
+                    drop(shared);
+                    drop(result);
+                    return;
+                }
+
+                shared.result = Some(result);
+                if let Some(waker) = shared.waker.take() {
+                    waker.wake();
+                }
+            });
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for LockFuture {
+    fn drop(&mut self) {
+        // dropping the acquisition right after stops the thread from waiting
+        self.shared
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .is_dropped = true;
+    }
+}
@@ -0,0 +1,94 @@
+use backend::{self, LockBackend, LockInfo, LockKind};
+use std::io::Error;
+use std::os::unix::io::RawFd;
+
+/// The built-in locking mechanisms
+///
+/// The modes differ in who owns the lock, which matters as soon as more than
+/// one thread, or more than one open file, is involved. Each mode forwards to
+/// the [`LockBackend`] of the same name in the [`backend`](backend/index.html) module.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LockMode {
+    /// Classic POSIX record locks via `F_SETLK`/`F_SETLKW`
+    ///
+    /// These are owned by the process: the kernel lets all threads share
+    /// them, and closing *any* file descriptor of the process referring to
+    /// the file releases them. [`FileLock`](struct.FileLock.html) keeps track
+    /// of the ranges its threads hold, so they exclude each other just like
+    /// other processes do. Threads waiting for each other in a cycle get
+    /// [`LockError::Deadlock`](enum.LockError.html#variant.Deadlock) as
+    /// well, though only when all of the locks involved are on the same file.
+    #[default]
+    Posix,
+    /// Linux open file description locks via `F_OFD_SETLK`/`F_OFD_SETLKW`
+    ///
+    /// These are owned by the open file, so they exclude other threads which
+    /// opened the file on their own, and are only released when the locked
+    /// file itself is unlocked or closed. Fails with
+    /// [`LockError::Unsupported`](enum.LockError.html#variant.Unsupported)
+    /// where the platform has no such locks, and with `EINVAL` on Linux
+    /// kernels older than 3.15.
+    Ofd,
+    /// BSD style whole-file locks via `flock(2)`
+    ///
+    /// These are owned by the open file like [`LockMode::Ofd`], but are a
+    /// separate mechanism which does not interact with `fcntl` locks. Use this
+    /// to cooperate with `flock(1)` in shell scripts. Byte ranges are not
+    /// supported, locking anything but the whole file fails with `EINVAL`.
+    Flock,
+    /// Exclusive-only [`LockMode::Posix`] locks, for code written against `lockf(3)`
+    ///
+    /// `lockf()` itself is never called, as it locks from the file offset on,
+    /// which all users of the open file share. This takes out an exclusive
+    /// `fcntl()` record lock instead, and shared locks fail with `EINVAL`.
+    /// The range is relative to the start of the file, and the file offset
+    /// is never touched. POSIX leaves open whether these exclude locks taken
+    /// out by `lockf()` itself; on Linux and the BSDs they do.
+    Lockf,
+}
+
+impl LockMode {
+    fn backend(self) -> &'static dyn LockBackend {
+        match self {
+            LockMode::Posix => &backend::Posix,
+            LockMode::Ofd => &backend::Ofd,
+            LockMode::Flock => &backend::Flock,
+            LockMode::Lockf => &backend::Lockf,
+        }
+    }
+}
+
+impl LockBackend for LockMode {
+    fn lock(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        is_blocking: bool,
+        start: u64,
+        len: u64,
+    ) -> Result<(), Error> {
+        self.backend().lock(fd, kind, is_blocking, start, len)
+    }
+
+    fn unlock(&self, fd: RawFd, start: u64, len: u64) -> Result<(), Error> {
+        self.backend().unlock(fd, start, len)
+    }
+
+    fn query(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        start: u64,
+        len: u64,
+    ) -> Result<Option<LockInfo>, Error> {
+        self.backend().query(fd, kind, start, len)
+    }
+
+    fn needs_access_mode(&self) -> bool {
+        self.backend().needs_access_mode()
+    }
+
+    fn is_process_owned(&self) -> bool {
+        self.backend().is_process_owned()
+    }
+}
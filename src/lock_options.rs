@@ -0,0 +1,375 @@
+use backend::{LockBackend, LockKind};
+use cancel::CancelToken;
+use error::{LockError, Operation};
+use file_options::FileOptions;
+use lock_mode::LockMode;
+use replace;
+use retry::RetryPolicy;
+use sidecar::{Sidecar, SidecarGuard};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use wait::Wait;
+use FileLock;
+
+/// Everything about how to take out a [`FileLock`](struct.FileLock.html), in one place
+///
+/// By default this waits for an exclusive POSIX record lock on the whole
+/// file, and opens the file for reading and writing, creating it if
+/// needed. Each setting can be changed on its own, and the options can be
+/// used to lock any number of files.
+///
+/// # Examples
+///
+///```
+///extern crate file_lock;
+///
+///use file_lock::{LockMode, LockOptions};
+///use std::io::prelude::*;
+///use std::time::Duration;
+///
+///fn main() {
+///    let mut filelock = match LockOptions::new()
+///        .exclusive()
+///        .timeout(Duration::from_secs(1))
+///        .range(0, 64)
+///        .backend(LockMode::Ofd)
+///        .lock("myfile.txt")
+///    {
+///        Ok(lock) => lock,
+///        Err(err) => panic!("Error getting write lock: {}", err),
+///    };
+///
+///    filelock.write_all(b"Hello, World!").is_ok();
+///}
+///```
+#[derive(Debug)]
+pub struct LockOptions<B = LockMode> {
+    kind: Option<LockKind>,
+    wait: Wait,
+    is_interruptible: bool,
+    is_verified: bool,
+    start: u64,
+    len: u64,
+    backend: B,
+    file_options: Option<FileOptions>,
+    sidecar: Option<Sidecar>,
+}
+
+impl LockOptions {
+    /// Start out with the defaults
+    pub fn new() -> Self {
+        LockOptions {
+            kind: None,
+            wait: Wait::Blocking,
+            is_interruptible: false,
+            is_verified: false,
+            start: 0,
+            len: 0,
+            backend: LockMode::Posix,
+            file_options: None,
+            sidecar: None,
+        }
+    }
+}
+
+impl Default for LockOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<B: LockBackend + 'static> LockOptions<B> {
+    /// Take out a shared or an exclusive lock
+    ///
+    /// Without this, the kind of lock follows the [`FileOptions`] as
+    /// described there, or is exclusive if there are none.
+    pub fn kind(mut self, kind: LockKind) -> Self {
+        self.kind = Some(kind);
+        self
+    }
+
+    /// Take out a shared lock, see [`LockOptions::kind`]
+    pub fn shared(self) -> Self {
+        self.kind(LockKind::Shared)
+    }
+
+    /// Take out an exclusive lock, see [`LockOptions::kind`]
+    pub fn exclusive(self) -> Self {
+        self.kind(LockKind::Exclusive)
+    }
+
+    /// Whether to wait for a lock held elsewhere, or to fail right away
+    ///
+    /// This is the default, and overrides any timeout or cancellation set before.
+    pub fn blocking(mut self, is_blocking: bool) -> Self {
+        self.wait = Wait::from_blocking(is_blocking);
+        self
+    }
+
+    /// Wait at most `timeout` for the lock
+    ///
+    /// This sets the deadline of the [`RetryPolicy`] in use. See
+    /// [`FileLock::lock_timeout`](struct.FileLock.html#method.lock_timeout) for details.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.wait = match self.wait {
+            Wait::Poll { policy, cancel } => Wait::Poll {
+                policy: policy.deadline(timeout),
+                cancel,
+            },
+            _ => Wait::timeout(timeout),
+        };
+        self
+    }
+
+    /// Stop waiting for the lock once `cancel` is triggered
+    ///
+    /// Can be combined with [`LockOptions::timeout`]. See
+    /// [`FileLock::lock_cancellable`](struct.FileLock.html#method.lock_cancellable) for details.
+    pub fn cancellable(mut self, cancel: &CancelToken) -> Self {
+        self.wait = match self.wait {
+            Wait::Poll { policy, .. } => Wait::Poll {
+                policy,
+                cancel: Some(cancel.clone()),
+            },
+            _ => Wait::cancellable(cancel),
+        };
+        self
+    }
+
+    /// Whether a blocking wait should end with
+    /// [`LockError::Interrupted`](enum.LockError.html#variant.Interrupted)
+    /// once a signal arrives
+    ///
+    /// By default, waiting just carries on after the signal has been handled.
+    /// Waiting with a timeout, cancellation or [`RetryPolicy`] is never
+    /// interrupted, and neither is waiting for a lock another thread of the
+    /// process holds through [`LockMode::Posix`].
+    pub fn interruptible(mut self, is_interruptible: bool) -> Self {
+        self.is_interruptible = is_interruptible;
+        self
+    }
+
+    /// Retry non-blocking attempts as told by `policy` rather than blocking
+    ///
+    /// Replaces any timeout set before, but keeps any cancellation.
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.wait = match self.wait {
+            Wait::Poll { cancel, .. } => Wait::Poll { policy, cancel },
+            _ => Wait::Poll {
+                policy,
+                cancel: None,
+            },
+        };
+        self
+    }
+
+    /// Lock only `len` bytes starting at `start`, where a `len` of `0` means
+    /// up to the end of the file
+    ///
+    /// See [`FileLock::lock_range`](struct.FileLock.html#method.lock_range) for details.
+    pub fn range(mut self, start: u64, len: u64) -> Self {
+        self.start = start;
+        self.len = len;
+        self
+    }
+
+    /// Whether to make sure the lock ends up on the file which is at the path
+    /// by the time we hold it
+    ///
+    /// Should the file be removed, or another one renamed over it, while we
+    /// open and wait for it, the lock would be on a file no one else is going
+    /// to lock anymore. With this, the path is checked again once locked,
+    /// and if it refers to another file by then, or none at all, we unlock
+    /// and start over with the file at the path now. This is what makes lock
+    /// files safe to remove while others may be waiting for them.
+    ///
+    /// Any timeout covers all attempts. Of no use with [`LockOptions::lock_file`].
+    pub fn verify(mut self, is_verified: bool) -> Self {
+        self.is_verified = is_verified;
+        self
+    }
+
+    /// Lock using the given [`LockBackend`] rather than POSIX record locks
+    pub fn backend<C: LockBackend + 'static>(self, backend: C) -> LockOptions<C> {
+        LockOptions {
+            kind: self.kind,
+            wait: self.wait,
+            is_interruptible: self.is_interruptible,
+            is_verified: self.is_verified,
+            start: self.start,
+            len: self.len,
+            backend,
+            file_options: self.file_options,
+            sidecar: self.sidecar,
+        }
+    }
+
+    /// Open the file to lock with `file_options`
+    ///
+    /// Without this, the file is opened for reading only for a shared lock,
+    /// and for reading and writing otherwise, creating it if needed.
+    pub fn file_options(mut self, file_options: FileOptions) -> Self {
+        self.file_options = Some(file_options);
+        self
+    }
+
+    /// Name, create and clean up sidecars as told by `sidecar`
+    ///
+    /// Only used by [`LockOptions::lock_sidecar`], which uses the
+    /// [`Sidecar`] defaults without this.
+    pub fn sidecar(mut self, sidecar: Sidecar) -> Self {
+        self.sidecar = Some(sidecar);
+        self
+    }
+
+    /// Open and lock the specified file
+    pub fn lock<P: AsRef<Path>>(self, path: P) -> Result<FileLock, LockError> {
+        let wait = self.wait();
+        let options = open_options(self.file_options, self.kind);
+
+        match self.is_verified {
+            true => {
+                FileLock::acquire_verified(path, wait, options, self.start, self.len, self.backend)
+            }
+            false => FileLock::acquire(path, wait, options, self.start, self.len, self.backend),
+        }
+    }
+
+    /// Lock the sidecar of the specified file, then open the file itself
+    ///
+    /// The sidecar is created if needed, and locked exclusively unless asked
+    /// for a shared lock with [`LockOptions::kind`]. The file options apply
+    /// to the file itself, which is only opened once the sidecar is locked,
+    /// so the handle refers to whatever file is at `path` by then. See
+    /// [`Sidecar`] for details.
+    pub fn lock_sidecar<P: AsRef<Path>>(
+        mut self,
+        path: P,
+    ) -> Result<(SidecarGuard, File), LockError> {
+        let path = path.as_ref();
+        let options = open_options(self.file_options.take(), self.kind);
+        let guard = self.sidecar_guard(path)?;
+
+        let file = options.open(path).map_err(|source| LockError::Open {
+            path: path.to_path_buf(),
+            source,
+        })?;
+
+        Ok((guard, file))
+    }
+
+    /// Replace the contents of the specified file all at once
+    ///
+    /// Under an exclusive lock on the file's sidecar, `write` fills a
+    /// temporary file in the same directory, which is then synced to disk and
+    /// renamed over the file. Readers see either the old or the new contents,
+    /// never anything in between, provided they open the file anew each time.
+    /// Should `write` fail or panic, the temporary file is removed and the
+    /// file is left alone. The new file keeps the permissions of the old
+    /// one, or is only accessible by its owner if there was none.
+    ///
+    /// The lock kind and file options are of no use here. See [`Sidecar`]
+    /// for details on the lock.
+    ///
+    /// # Examples
+    ///
+    ///```
+    ///extern crate file_lock;
+    ///
+    ///use file_lock::LockOptions;
+    ///use std::io::prelude::*;
+    ///
+    ///fn main() {
+    ///    let written = LockOptions::new().replace("myfile.txt", |file| file.write_all(b"Hello, World!"));
+    ///
+    ///    if let Err(err) = written {
+    ///        panic!("Error replacing the file: {}", err);
+    ///    }
+    ///}
+    ///```
+    pub fn replace<P, T, F>(self, path: P, write: F) -> Result<T, LockError>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut File) -> io::Result<T>,
+    {
+        let path = path.as_ref();
+        let guard = self.exclusive().sidecar_guard(path)?;
+
+        let written = replace::replace(path, write)
+            .map_err(|err| LockError::from_io(Operation::Replace, err).with_path(Some(path)))?;
+        guard.unlock()?;
+
+        Ok(written)
+    }
+
+    /// Lock an already open file
+    ///
+    /// The file options are of no use here, and without a [`LockOptions::kind`]
+    /// the lock is shared if `file` is open for reading only, and exclusive
+    /// otherwise. See [`FileLock::lock_file`](struct.FileLock.html#method.lock_file) for details.
+    pub fn lock_file<F: Into<File>>(self, file: F) -> Result<FileLock, LockError> {
+        let file = file.into();
+        let kind = match self.kind {
+            Some(kind) => kind,
+            None => FileOptions::new().kind(&file),
+        };
+
+        let wait = self.wait();
+
+        FileLock::acquire_file(file, wait, kind, self.start, self.len, self.backend)
+    }
+
+    /// Lock the sidecar of `path`, see [`LockOptions::lock_sidecar`]
+    fn sidecar_guard(self, path: &Path) -> Result<SidecarGuard, LockError> {
+        let wait = self.wait();
+        let kind = self.kind.unwrap_or(LockKind::Exclusive);
+        let sidecar = self.sidecar.unwrap_or_default();
+        let sidecar_path = sidecar.path_for(path);
+        let options = sidecar.file_options().lock_kind(kind);
+
+        // someone else may clean up the sidecar while we wait for it
+        let lock = match self.is_verified || sidecar.is_cleanup() {
+            true => FileLock::acquire_verified(
+                &sidecar_path,
+                wait,
+                options,
+                self.start,
+                self.len,
+                self.backend,
+            ),
+            false => FileLock::acquire(
+                &sidecar_path,
+                wait,
+                options,
+                self.start,
+                self.len,
+                self.backend,
+            ),
+        }?;
+
+        Ok(SidecarGuard::new(lock, sidecar_path, sidecar.is_cleanup()))
+    }
+
+    fn wait(&self) -> Wait {
+        match self.wait {
+            Wait::Blocking if self.is_interruptible => Wait::Interruptible,
+            ref wait => wait.clone(),
+        }
+    }
+}
+
+/// How to open the file to lock, see [`LockOptions::file_options`]
+fn open_options(file_options: Option<FileOptions>, kind: Option<LockKind>) -> FileOptions {
+    let options = match file_options {
+        Some(options) => options,
+        None if kind == Some(LockKind::Shared) => FileOptions::new().read(true),
+        None => FileOptions::new().read(true).write(true).create(true),
+    };
+
+    match kind {
+        Some(kind) => options.lock_kind(kind),
+        None => options,
+    }
+}
@@ -0,0 +1,185 @@
+use backend::{LockBackend, LockKind};
+use error::{LockError, Operation};
+use file_options::Access;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use registry::{Owner, Registered};
+use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+use std::time::Duration;
+use wait::{self, Wait};
+
+/// Everything we need to know about a lock we hold, apart from the file it is held on
+#[derive(Debug)]
+pub(crate) struct LockState {
+    pub(crate) backend: Box<dyn LockBackend>,
+    pub(crate) kind: LockKind,
+    pub(crate) start: u64,
+    pub(crate) len: u64,
+    pub(crate) waited: Duration,
+    pub(crate) attempts: u32,
+    pub(crate) path: Option<PathBuf>,
+    pub(crate) owner: Option<Owner>,
+    /// Cleared when a failed conversion lost the lock altogether
+    pub(crate) is_held: bool,
+    /// What the file was opened for, if we opened it ourselves
+    pub(crate) access: Option<Access>,
+}
+
+impl LockState {
+    /// Lock the given range of `fd` as told by `wait`
+    ///
+    /// `path` is only used to tell which file an error is about.
+    pub(crate) fn acquire(
+        fd: RawFd,
+        kind: LockKind,
+        wait: Wait,
+        start: u64,
+        len: u64,
+        backend: Box<dyn LockBackend>,
+        path: Option<PathBuf>,
+    ) -> Result<LockState, LockError> {
+        Self::try_acquire(fd, kind, wait, start, len, backend, path).map_err(|(err, _)| err)
+    }
+
+    /// Like [`LockState::acquire`], but hand `backend` back should locking fail
+    pub(crate) fn try_acquire(
+        fd: RawFd,
+        kind: LockKind,
+        wait: Wait,
+        start: u64,
+        len: u64,
+        backend: Box<dyn LockBackend>,
+        path: Option<PathBuf>,
+    ) -> Result<LockState, (LockError, Box<dyn LockBackend>)> {
+        let locked = check_access_mode(&*backend, fd, kind, Operation::Lock).and_then(|_| {
+            let owner = Owner::new(&*backend, fd)
+                .map_err(|err| LockError::from_io(Operation::Lock, err))?;
+            let registered = Registered {
+                inner: &*backend,
+                owner,
+            };
+
+            wait::lock(&registered, fd, kind, wait, start, len, Operation::Lock)
+                .map(|(waited, attempts)| (waited, attempts, owner))
+        });
+
+        match locked {
+            Ok((waited, attempts, owner)) => Ok(LockState {
+                backend,
+                kind,
+                start,
+                len,
+                waited,
+                attempts,
+                path,
+                owner,
+                is_held: true,
+                access: None,
+            }),
+            Err(err) => Err((err.with_path(path.as_deref()), backend)),
+        }
+    }
+
+    /// Turn our shared lock into an exclusive one, or the other way around,
+    /// without letting go of it in between
+    ///
+    /// If the lock was lost, this takes out a new one of the given `kind`.
+    pub(crate) fn convert(
+        &mut self,
+        fd: RawFd,
+        kind: LockKind,
+        wait: Wait,
+    ) -> Result<(), LockError> {
+        if kind == self.kind && self.is_held {
+            return Ok(());
+        }
+
+        let operation = match kind {
+            LockKind::Exclusive => Operation::Upgrade,
+            LockKind::Shared => Operation::Downgrade,
+        };
+
+        check_access_mode(&*self.backend, fd, kind, operation)
+            .map_err(|err| err.with_path(self.path.as_deref()))?;
+
+        match wait::lock(
+            &self.registered(),
+            fd,
+            kind,
+            wait,
+            self.start,
+            self.len,
+            operation,
+        ) {
+            Ok(_) => {
+                self.kind = kind;
+                self.is_held = true;
+                Ok(())
+            }
+            Err(err) if !self.is_held => Err(err.with_path(self.path.as_deref())),
+            Err(err) => {
+                // a no-op for fcntl(), but flock() may have dropped our lock
+                match self
+                    .backend
+                    .lock(fd, self.kind, false, self.start, self.len)
+                {
+                    Ok(()) => Err(err.with_path(self.path.as_deref())),
+                    Err(source) => {
+                        self.is_held = false;
+                        Err(LockError::Lost {
+                            operation,
+                            path: self.path.clone(),
+                            source,
+                        })
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn release(&self, fd: RawFd) -> Result<(), LockError> {
+        self.registered()
+            .unlock(fd, self.start, self.len)
+            .map_err(|err| {
+                LockError::from_io(Operation::Unlock, err).with_path(self.path.as_deref())
+            })
+    }
+
+    /// Our backend, keeping track of what our threads hold where needed
+    fn registered(&self) -> Registered<'_> {
+        Registered {
+            inner: &*self.backend,
+            owner: self.owner,
+        }
+    }
+}
+
+/// Fail right away if `fd` isn't open for the access a `kind` lock needs
+fn check_access_mode(
+    backend: &dyn LockBackend,
+    fd: RawFd,
+    kind: LockKind,
+    operation: Operation,
+) -> Result<(), LockError> {
+    if !backend.needs_access_mode() {
+        return Ok(());
+    }
+
+    let flags = fcntl(fd, FcntlArg::F_GETFL)
+        .map_err(|errno| LockError::from_io(operation, errno.into()))?;
+    let access = OFlag::from_bits_truncate(flags) & OFlag::O_ACCMODE;
+
+    let is_allowed = match kind {
+        LockKind::Shared => access != OFlag::O_WRONLY,
+        LockKind::Exclusive => access != OFlag::O_RDONLY,
+    };
+
+    match is_allowed {
+        true => Ok(()),
+        false => Err(LockError::AccessMode {
+            operation,
+            path: None,
+            kind,
+        }),
+    }
+}
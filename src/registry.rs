@@ -0,0 +1,453 @@
+use backend::{LockBackend, LockInfo, LockKind};
+use file_options::{Access, FileOptions};
+use nix::sys::stat::fstat;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Error, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+
+/// Identifies a file independent of the path or descriptor it was opened by
+type FileId = (u64, u64);
+
+/// Who holds a lock on which file, as far as the registry is concerned
+///
+/// Each owner holds at most one lock on the file, which locking again replaces.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Owner {
+    file: FileId,
+    id: u64,
+}
+
+/// A lock held by one of our owners
+#[derive(Clone, Copy, Debug)]
+struct Held {
+    owner: u64,
+    kind: LockKind,
+    start: u64,
+    len: u64,
+}
+
+/// Everything we know about a file some of our owners hold locks on
+#[derive(Debug, Default)]
+struct Entry {
+    held: Vec<Held>,
+    /// The locks our owners are blocked waiting for
+    waiting: Vec<Held>,
+    /// Descriptors we are done with, but which can't be closed just yet,
+    /// along with what they were opened for if known
+    ///
+    /// Closing any descriptor of a file releases all POSIX locks the
+    /// process holds on it, so these are kept open until the last lock on
+    /// the file is gone. Until then, [`open`] hands them out again rather
+    /// than opening yet another descriptor each time.
+    idle: Vec<(Option<Access>, File)>,
+}
+
+#[derive(Debug)]
+struct Registry {
+    /// The process the locks belong to, as they aren't inherited across `fork()`
+    pid: u32,
+    locks: HashMap<FileId, Entry>,
+}
+
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+static RELEASED: Condvar = Condvar::new();
+static NEXT_OWNER: AtomicU64 = AtomicU64::new(0);
+
+impl Owner {
+    /// A new owner for locking `fd` using `backend`, if its locks need to be registered
+    pub(crate) fn new(backend: &dyn LockBackend, fd: RawFd) -> Result<Option<Owner>, Error> {
+        if !backend.is_process_owned() {
+            return Ok(None);
+        }
+
+        Ok(Some(Owner {
+            file: file_id(fd)?,
+            id: NEXT_OWNER.fetch_add(1, Ordering::Relaxed),
+        }))
+    }
+}
+
+fn file_id(fd: RawFd) -> Result<FileId, Error> {
+    let stat = fstat(fd)?;
+
+    Ok((stat.st_dev as u64, stat.st_ino as u64))
+}
+
+/// Open `path` as told by `options`, using an idle descriptor of the file if we have one
+///
+/// Use this rather than opening any file which might be locked elsewhere
+/// in the process, so the number of descriptors kept open by [`close`]
+/// stays bounded.
+pub(crate) fn open(path: &Path, options: &FileOptions) -> Result<File, Error> {
+    match reuse(path, options) {
+        Some(file) => Ok(file),
+        None => options.open(path),
+    }
+}
+
+fn reuse(path: &Path, options: &FileOptions) -> Option<File> {
+    let access = options.access()?;
+    let metadata = match access.is_nofollow() {
+        true => fs::symlink_metadata(path),
+        false => fs::metadata(path),
+    }
+    .ok()?;
+
+    // opening it fails
+    if metadata.file_type().is_symlink() {
+        return None;
+    }
+
+    let mut file = {
+        let mut registry = locks();
+        let entry = registry
+            .as_mut()?
+            .locks
+            .get_mut(&(metadata.dev(), metadata.ino()))?;
+        let index = entry
+            .idle
+            .iter()
+            .position(|&(idle, _)| idle == Some(access))?;
+
+        entry.idle.swap_remove(index).1
+    };
+
+    // just like freshly opened
+    let reset = file
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| match options.is_truncate() {
+            true => file.set_len(0),
+            false => Ok(()),
+        });
+
+    match reset {
+        Ok(()) => Some(file),
+        Err(_) => {
+            close(file, Some(access));
+            None
+        }
+    }
+}
+
+/// Close `file` once doing so no longer releases a lock held by one of our owners
+///
+/// Use this rather than dropping any file which might be locked elsewhere
+/// in the process. `access` tells what the file was opened for, if it was
+/// opened by [`open`], so that it can be handed out again in the meantime.
+pub(crate) fn close(file: File, access: Option<Access>) {
+    let id = match file_id(file.as_raw_fd()) {
+        Ok(id) => id,
+        Err(_) => return,
+    };
+
+    if let Some(ref mut registry) = *locks() {
+        if let Some(entry) = registry.locks.get_mut(&id) {
+            entry.idle.push((access, file));
+        }
+    }
+}
+
+fn locks() -> MutexGuard<'static, Option<Registry>> {
+    let mut registry = REGISTRY.lock().unwrap_or_else(|err| err.into_inner());
+    let pid = process::id();
+
+    if registry.as_ref().map(|registry| registry.pid) != Some(pid) {
+        *registry = Some(Registry {
+            pid,
+            locks: HashMap::new(),
+        });
+    }
+    registry
+}
+
+fn end(start: u64, len: u64) -> u64 {
+    match len {
+        0 => u64::MAX,
+        _ => start.saturating_add(len),
+    }
+}
+
+fn overlaps(held: &Held, start: u64, len: u64) -> bool {
+    held.start < end(start, len) && start < end(held.start, held.len)
+}
+
+/// The locks of other owners which keep `owner` from locking the given range as `kind`
+fn conflicts(
+    held: &[Held],
+    owner: u64,
+    kind: LockKind,
+    start: u64,
+    len: u64,
+) -> impl Iterator<Item = &Held> {
+    held.iter().filter(move |held| {
+        held.owner != owner
+            && (kind == LockKind::Exclusive || held.kind == LockKind::Exclusive)
+            && overlaps(held, start, len)
+    })
+}
+
+fn conflict(held: &[Held], owner: u64, kind: LockKind, start: u64, len: u64) -> Option<&Held> {
+    conflicts(held, owner, kind, start, len).next()
+}
+
+/// Whether waiting for `wanted` would never end, as those in the way wait for its owner in turn
+///
+/// This is what `fcntl()` reports as `EDEADLK` between processes.
+fn is_deadlock(entry: &Entry, wanted: &Held) -> bool {
+    let mut blockers: Vec<u64> = conflicts(
+        &entry.held,
+        wanted.owner,
+        wanted.kind,
+        wanted.start,
+        wanted.len,
+    )
+    .map(|held| held.owner)
+    .collect();
+    let mut seen = Vec::new();
+
+    while let Some(blocker) = blockers.pop() {
+        if blocker == wanted.owner {
+            return true;
+        }
+        if seen.contains(&blocker) {
+            continue;
+        }
+        seen.push(blocker);
+
+        for waiting in entry
+            .waiting
+            .iter()
+            .filter(|waiting| waiting.owner == blocker)
+        {
+            blockers.extend(
+                conflicts(
+                    &entry.held,
+                    blocker,
+                    waiting.kind,
+                    waiting.start,
+                    waiting.len,
+                )
+                .map(|held| held.owner),
+            );
+        }
+    }
+    false
+}
+
+/// The parts of the given range no other owner holds a lock on, as `(start, len)`
+///
+/// Only these may be unlocked, as the process has a single lock on each byte.
+fn uncovered(held: &[Held], owner: Owner, start: u64, len: u64) -> Vec<(u64, u64)> {
+    let mut others: Vec<(u64, u64)> = held
+        .iter()
+        .filter(|held| held.owner != owner.id && overlaps(held, start, len))
+        .map(|held| (held.start, end(held.start, held.len)))
+        .collect();
+    others.sort();
+
+    let mut ranges = Vec::new();
+    let mut next = start;
+    for (other_start, other_end) in others {
+        if next < other_start {
+            ranges.push((next, other_start - next));
+        }
+        next = next.max(other_end);
+    }
+
+    let end = end(start, len);
+    if next < end {
+        // up to the end of the file stays that way
+        ranges.push((next, if end == u64::MAX { 0 } else { end - next }));
+    }
+    ranges
+}
+
+/// A backend which only locks what no other thread of ours holds, then defers to `inner`
+///
+/// Without an owner, this is just `inner`.
+#[derive(Debug)]
+pub(crate) struct Registered<'a> {
+    pub(crate) inner: &'a dyn LockBackend,
+    pub(crate) owner: Option<Owner>,
+}
+
+impl<'a> Registered<'a> {
+    /// Register our lock as `kind`, returning what we held before
+    fn register(
+        owner: Owner,
+        kind: LockKind,
+        is_blocking: bool,
+        start: u64,
+        len: u64,
+    ) -> Result<Option<Held>, Error> {
+        let mut registry = locks();
+
+        let wanted = Held {
+            owner: owner.id,
+            kind,
+            start,
+            len,
+        };
+
+        loop {
+            let entry = registry
+                .as_mut()
+                .expect("registry is set up")
+                .locks
+                .entry(owner.file)
+                .or_default();
+            let locks = &mut entry.held;
+
+            if conflict(locks, owner.id, kind, start, len).is_none() {
+                let previous = locks
+                    .iter()
+                    .position(|held| held.owner == owner.id)
+                    .map(|index| locks.swap_remove(index));
+
+                locks.push(Held {
+                    owner: owner.id,
+                    kind,
+                    start,
+                    len,
+                });
+
+                // a downgrade may let others in
+                if previous.is_some() {
+                    RELEASED.notify_all();
+                }
+                return Ok(previous);
+            }
+
+            if !is_blocking {
+                return Err(Error::from_raw_os_error(libc::EAGAIN));
+            }
+            if is_deadlock(entry, &wanted) {
+                return Err(Error::from_raw_os_error(libc::EDEADLK));
+            }
+
+            entry.waiting.push(wanted);
+            registry = RELEASED
+                .wait(registry)
+                .unwrap_or_else(|err| err.into_inner());
+
+            if let Some(entry) = registry
+                .as_mut()
+                .and_then(|registry| registry.locks.get_mut(&owner.file))
+            {
+                entry.waiting.retain(|waiting| waiting.owner != owner.id);
+            }
+        }
+    }
+
+    /// Forget about our lock, going back to `previous` if there is one
+    ///
+    /// Returns the file's entry if that was the last lock on it, so that
+    /// its idle descriptors are closed after the registry is unlocked.
+    fn unregister(
+        registry: &mut Option<Registry>,
+        owner: Owner,
+        previous: Option<Held>,
+    ) -> Option<Entry> {
+        let registry = registry.as_mut()?;
+        let entry = registry.locks.get_mut(&owner.file)?;
+
+        entry.held.retain(|held| held.owner != owner.id);
+        entry.held.extend(previous);
+
+        // whoever waits for us may go ahead, or at least check again
+        RELEASED.notify_all();
+
+        match entry.held.is_empty() {
+            true => registry.locks.remove(&owner.file),
+            false => None,
+        }
+    }
+}
+
+impl<'a> LockBackend for Registered<'a> {
+    fn lock(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        is_blocking: bool,
+        start: u64,
+        len: u64,
+    ) -> Result<(), Error> {
+        let owner = match self.owner {
+            Some(owner) => owner,
+            None => return self.inner.lock(fd, kind, is_blocking, start, len),
+        };
+        let previous = Self::register(owner, kind, is_blocking, start, len)?;
+
+        self.inner
+            .lock(fd, kind, is_blocking, start, len)
+            .inspect_err(|_| {
+                let mut registry = locks();
+                let released = Self::unregister(&mut registry, owner, previous);
+                drop(registry);
+                drop(released);
+            })
+    }
+
+    fn unlock(&self, fd: RawFd, start: u64, len: u64) -> Result<(), Error> {
+        let owner = match self.owner {
+            Some(owner) => owner,
+            None => return self.inner.unlock(fd, start, len),
+        };
+
+        // unlocking never waits, so this can keep others from locking
+        // what we are about to unlock in the meantime
+        let mut registry = locks();
+        let ranges = match registry
+            .as_ref()
+            .and_then(|registry| registry.locks.get(&owner.file))
+        {
+            Some(entry) => uncovered(&entry.held, owner, start, len),
+            None => vec![(start, len)],
+        };
+
+        let unlocked = ranges
+            .into_iter()
+            .try_for_each(|(start, len)| self.inner.unlock(fd, start, len));
+        let released = Self::unregister(&mut registry, owner, None);
+        drop(registry);
+        drop(released);
+
+        unlocked
+    }
+
+    fn query(
+        &self,
+        fd: RawFd,
+        kind: LockKind,
+        start: u64,
+        len: u64,
+    ) -> Result<Option<LockInfo>, Error> {
+        if let (Some(owner), Some(ref registry)) = (self.owner, &*locks()) {
+            let entry = registry.locks.get(&owner.file);
+
+            if let Some(held) =
+                entry.and_then(|entry| conflict(&entry.held, owner.id, kind, start, len))
+            {
+                return Ok(Some(LockInfo {
+                    kind: held.kind,
+                    start: held.start,
+                    len: held.len,
+                    pid: Some(registry.pid),
+                }));
+            }
+        }
+
+        self.inner.query(fd, kind, start, len)
+    }
+
+    fn is_process_owned(&self) -> bool {
+        self.inner.is_process_owned()
+    }
+}
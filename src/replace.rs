@@ -0,0 +1,39 @@
+use mktemp::Temp;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+/// Write new contents for `path` with `write`, then rename them into place
+///
+/// The caller holds the lock. Until the rename, the new file is removed again
+/// on any error, and when unwinding from a panic in `write`.
+pub(crate) fn replace<T, F>(path: &Path, write: F) -> io::Result<T>
+where
+    F: FnOnce(&mut File) -> io::Result<T>,
+{
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+
+    let temp = Temp::new_file_in(dir)?;
+    let mut file = OpenOptions::new().write(true).open(&temp)?;
+
+    match fs::metadata(path) {
+        Ok(metadata) => file.set_permissions(metadata.permissions())?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    let written = write(&mut file)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&temp, path)?;
+    temp.release();
+
+    // make the rename itself durable
+    File::open(dir)?.sync_all()?;
+
+    Ok(written)
+}
@@ -0,0 +1,128 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// How often, and for how long, to retry taking a contended lock
+///
+/// Each attempt is a non-blocking one, with a pause in between, so this
+/// never ends up in `F_SETLKW`. That is what you want where blocking locks
+/// are unreliable, such as on NFS, or where signals would keep interrupting
+/// them. Pauses are either fixed, or double after every attempt up to a
+/// limit, and may be randomized a little so that several waiters don't
+/// retry in lockstep.
+///
+/// Without a limit, retrying goes on until the lock is ours. Running out of
+/// attempts fails with [`LockError::RetriesExhausted`](enum.LockError.html#variant.RetriesExhausted),
+/// running past the deadline with [`LockError::TimedOut`](enum.LockError.html#variant.TimedOut).
+/// A [`FileLock`](struct.FileLock.html) tells how many attempts it took.
+///
+/// # Examples
+///
+///```
+///extern crate file_lock;
+///
+///use file_lock::{LockOptions, RetryPolicy};
+///use std::time::Duration;
+///
+///fn main() {
+///    let policy = RetryPolicy::exponential(Duration::from_millis(10), Duration::from_secs(1))
+///        .jitter(true)
+///        .max_attempts(10)
+///        .deadline(Duration::from_secs(5));
+///
+///    match LockOptions::new().retry(policy).lock("myfile.txt") {
+///        Ok(lock) => println!("Got the lock after {} attempts", lock.attempts()),
+///        Err(err) => panic!("Error getting write lock: {}", err),
+///    };
+///}
+///```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    interval: Duration,
+    max_interval: Duration,
+    has_jitter: bool,
+    max_attempts: Option<u32>,
+    deadline: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// Pause for `interval` between two attempts
+    pub fn fixed(interval: Duration) -> Self {
+        RetryPolicy {
+            interval,
+            max_interval: interval,
+            has_jitter: false,
+            max_attempts: None,
+            deadline: None,
+        }
+    }
+
+    /// Pause for `initial` after the first attempt, doubling the pause after
+    /// each further one up to `max_interval`
+    pub fn exponential(initial: Duration, max_interval: Duration) -> Self {
+        RetryPolicy {
+            interval: initial,
+            max_interval: max_interval.max(initial),
+            has_jitter: false,
+            max_attempts: None,
+            deadline: None,
+        }
+    }
+
+    /// Shorten each pause by a random amount of up to half of it
+    pub fn jitter(mut self, has_jitter: bool) -> Self {
+        self.has_jitter = has_jitter;
+        self
+    }
+
+    /// Give up after this many attempts, the first one included
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = Some(max_attempts);
+        self
+    }
+
+    /// Give up once this much time has passed since the first attempt
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    pub(crate) fn max_attempts_reached(&self, attempts: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempts >= max)
+    }
+
+    pub(crate) fn deadline_left(&self, waited: Duration) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_sub(waited))
+    }
+
+    /// How long to pause after the given attempt, counting from `1`
+    pub(crate) fn pause(&self, attempt: u32) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(31);
+        let pause = self
+            .interval
+            .checked_mul(1 << doublings)
+            .unwrap_or(self.max_interval)
+            .min(self.max_interval);
+
+        match self.has_jitter {
+            true => pause - pause.mul_f64(random_fraction(attempt) / 2.0),
+            false => pause,
+        }
+    }
+}
+
+/// Polls with exponential backoff from 1ms up to 50ms, for as long as it takes
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::exponential(Duration::from_millis(1), Duration::from_millis(50))
+    }
+}
+
+/// A random number in `0.0..1.0`, good enough to spread out retries
+fn random_fraction(attempt: u32) -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(attempt);
+
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
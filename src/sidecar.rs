@@ -0,0 +1,217 @@
+use backend::{LockBackend, LockKind};
+use error::LockError;
+use file_options::FileOptions;
+use registry;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::mem::ManuallyDrop;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::time::Duration;
+use FileLock;
+
+/// How to lock a path through a separate lock file next to it
+///
+/// Locking a file itself locks its inode, which is of no use once the file
+/// is replaced by renaming another one over it, as is common for atomic
+/// updates. Locking a sidecar such as `data.json.lock` instead guards the
+/// path, whatever file it currently refers to. The data file itself is
+/// never locked, so every process has to agree on locking the sidecar.
+///
+/// By default the sidecar is named after the file with `.lock` appended,
+/// created readable and writeable by its owner only, and left in place
+/// after unlocking. Used with [`LockOptions::lock_sidecar`](struct.LockOptions.html#method.lock_sidecar).
+///
+/// # Examples
+///
+///```
+///extern crate file_lock;
+///
+///use file_lock::{LockOptions, Sidecar};
+///use std::io::prelude::*;
+///
+///fn main() {
+///    let sidecar = Sidecar::new().suffix(".lck").cleanup(true);
+///
+///    let (guard, mut file) = match LockOptions::new().sidecar(sidecar).lock_sidecar("myfile.txt") {
+///        Ok(locked) => locked,
+///        Err(err) => panic!("Error getting write lock: {}", err),
+///    };
+///    assert!(guard.path().ends_with("myfile.txt.lck"));
+///
+///    file.write_all(b"Hello, World!").is_ok();
+///}
+///```
+#[derive(Clone, Debug)]
+pub struct Sidecar {
+    naming: Naming,
+    mode: u32,
+    is_cleanup: bool,
+}
+
+#[derive(Clone, Debug)]
+enum Naming {
+    Suffix(OsString),
+    Custom(fn(&Path) -> PathBuf),
+}
+
+impl Sidecar {
+    /// Start out with the defaults
+    pub fn new() -> Self {
+        Sidecar {
+            naming: Naming::Suffix(OsString::from(".lock")),
+            mode: 0o600,
+            is_cleanup: false,
+        }
+    }
+
+    /// Name the sidecar after the file with `suffix` appended
+    pub fn suffix<S: AsRef<OsStr>>(mut self, suffix: S) -> Self {
+        self.naming = Naming::Suffix(suffix.as_ref().to_os_string());
+        self
+    }
+
+    /// Name the sidecar of a file as told by `naming`
+    ///
+    /// This can put lock files into a directory of their own, for example.
+    /// The same file must always map to the same sidecar.
+    pub fn naming(mut self, naming: fn(&Path) -> PathBuf) -> Self {
+        self.naming = Naming::Custom(naming);
+        self
+    }
+
+    /// The permissions to create the sidecar with, `0o600` by default
+    ///
+    /// Other users can only share the lock if they may open the sidecar.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Whether to remove the sidecar when unlocking
+    ///
+    /// Only exclusive locks remove it, since other readers may still hold a
+    /// shared lock on it, and a writer locking a new sidecar in its place
+    /// would not wait for them.
+    ///
+    /// The sidecar is removed while still locked. Others waiting for it at
+    /// that time would end up with a lock on the removed file, so locking a
+    /// sidecar which is cleaned up always checks it, as described for
+    /// [`LockOptions::verify`](struct.LockOptions.html#method.verify). Every
+    /// process locking the sidecar must clean up or verify.
+    pub fn cleanup(mut self, is_cleanup: bool) -> Self {
+        self.is_cleanup = is_cleanup;
+        self
+    }
+
+    /// The sidecar to lock for `path`
+    pub fn path_for<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        let path = path.as_ref();
+
+        match self.naming {
+            Naming::Suffix(ref suffix) => {
+                let mut name = path.as_os_str().to_os_string();
+                name.push(suffix);
+                PathBuf::from(name)
+            }
+            Naming::Custom(naming) => naming(path),
+        }
+    }
+
+    /// How to open the sidecar, which is locked for reading and writing alike
+    pub(crate) fn file_options(&self) -> FileOptions {
+        FileOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .mode(self.mode)
+            .custom_flags(libc::O_NOFOLLOW)
+    }
+
+    pub(crate) fn is_cleanup(&self) -> bool {
+        self.is_cleanup
+    }
+}
+
+impl Default for Sidecar {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A lock on a sidecar, as returned by [`LockOptions::lock_sidecar`](struct.LockOptions.html#method.lock_sidecar)
+///
+/// The lock is held for as long as the guard exists, and released on drop
+/// or by [`SidecarGuard::unlock`], removing the sidecar first if asked to
+/// and the lock is exclusive.
+#[derive(Debug)]
+pub struct SidecarGuard {
+    lock: FileLock,
+    path: PathBuf,
+    is_cleanup: bool,
+}
+
+impl SidecarGuard {
+    pub(crate) fn new(lock: FileLock, path: PathBuf, is_cleanup: bool) -> Self {
+        SidecarGuard {
+            lock,
+            path,
+            is_cleanup,
+        }
+    }
+
+    /// The sidecar we hold a lock on
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The [`LockBackend`] this lock was taken out with
+    pub fn backend(&self) -> &dyn LockBackend {
+        self.lock.backend()
+    }
+
+    /// How long we had to wait for the lock to become available
+    pub fn waited(&self) -> Duration {
+        self.lock.waited()
+    }
+
+    /// How many attempts it took to get the lock
+    pub fn attempts(&self) -> u32 {
+        self.lock.attempts()
+    }
+
+    /// Whether we hold a shared or an exclusive lock
+    pub fn kind(&self) -> LockKind {
+        self.lock.kind()
+    }
+
+    /// Unlock the sidecar, removing it first if asked to and the lock is exclusive
+    ///
+    /// *Note:* This method is optional as the sidecar will be unlocked automatically when dropped
+    pub fn unlock(self) -> Result<(), LockError> {
+        self.remove();
+        let guard = ManuallyDrop::new(self);
+
+        // `guard` is never dropped, so each field is moved out exactly once
+        let (lock, _path) = unsafe { (ptr::read(&guard.lock), ptr::read(&guard.path)) };
+
+        let (file, state) = lock.into_parts();
+        let released = state.release(file.as_raw_fd());
+        registry::close(file, state.access);
+
+        released
+    }
+
+    fn remove(&self) {
+        if self.is_cleanup && self.kind() == LockKind::Exclusive {
+            let _ = fs::remove_file(&self.path).is_ok();
+        }
+    }
+}
+
+impl Drop for SidecarGuard {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
@@ -0,0 +1,380 @@
+use acquisition::Acquisition;
+use backend::{LockBackend, LockKind};
+use error::{LockError, Operation};
+use file_options::{Access, FileOptions};
+use lock_mode::LockMode;
+use lock_state::LockState;
+use registry;
+use retry::RetryPolicy;
+use std::fs;
+use std::future::Future;
+use std::io::{self, SeekFrom};
+use std::mem::ManuallyDrop;
+use std::ops::Deref;
+use std::os::unix::io::AsRawFd;
+use std::panic;
+use std::path::Path;
+use std::pin::Pin;
+use std::ptr;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf};
+use tokio::task::{self, JoinHandle};
+use FileLock;
+
+/// A lock on a file for use on a tokio runtime
+///
+/// Waiting for the lock happens on tokio's blocking thread pool, so the
+/// runtime's worker threads are never stalled. The lock reads, writes and
+/// seeks through the locked [`tokio::fs::File`], and derefs to it for
+/// everything else. The lock is released on drop or by
+/// [`AsyncFileLock::unlock`], which hands the file back.
+///
+/// A tokio file finishes writes in the background. Dropping the lock
+/// releases it once the last of them has landed, waiting on a thread of its
+/// own if need be, while [`AsyncFileLock::unlock`] waits for them in place
+/// and reports their errors.
+///
+/// Only available with the `tokio` feature.
+///
+/// # Examples
+///
+///```
+///extern crate file_lock;
+///extern crate tokio;
+///
+///use file_lock::{AsyncFileLock, FileOptions};
+///use tokio::io::AsyncWriteExt;
+///use tokio::runtime::Builder;
+///
+///fn main() {
+///    let runtime = Builder::new_current_thread().build().unwrap();
+///    let options = FileOptions::new().write(true).create(true);
+///
+///    let mut filelock = match runtime.block_on(AsyncFileLock::lock("myfile.txt", true, options)) {
+///        Ok(lock) => lock,
+///        Err(err) => panic!("Error getting write lock: {}", err),
+///    };
+///
+///    runtime.block_on(filelock.write_all(b"Hello, World!")).is_ok();
+///
+///    // waits for the write to land before unlocking
+///    runtime.block_on(filelock.unlock()).is_ok();
+///}
+///```
+#[derive(Debug)]
+pub struct AsyncFileLock {
+    file: ManuallyDrop<File>,
+    state: ManuallyDrop<LockState>,
+}
+
+impl AsyncFileLock {
+    /// Lock the specified file without blocking the runtime
+    ///
+    /// Takes the same parameters as [`FileLock::lock`](struct.FileLock.html#method.lock).
+    /// Nothing happens until the returned future is first polled, which must
+    /// be from within a tokio runtime. Dropping the future before it
+    /// completes gives up waiting, and a lock taken out in the meantime is
+    /// released again.
+    pub fn lock<P: AsRef<Path>>(
+        path: P,
+        is_blocking: bool,
+        options: FileOptions,
+    ) -> AsyncLockFuture {
+        Self::lock_with(path, is_blocking, options, LockMode::Posix)
+    }
+
+    /// Lock the specified file using the given [`LockBackend`] without blocking the runtime
+    ///
+    /// See [`AsyncFileLock::lock`] for details.
+    pub fn lock_with<P: AsRef<Path>, B: LockBackend + 'static>(
+        path: P,
+        is_blocking: bool,
+        options: FileOptions,
+        backend: B,
+    ) -> AsyncLockFuture {
+        AsyncLockFuture::new(Acquisition::new(
+            path.as_ref(),
+            is_blocking,
+            options,
+            backend,
+        ))
+    }
+
+    /// Lock the specified file without blocking the runtime, waiting at most `timeout`
+    ///
+    /// See [`AsyncFileLock::lock`] and [`FileLock::lock_timeout`](struct.FileLock.html#method.lock_timeout) for details.
+    pub fn lock_timeout<P: AsRef<Path>>(
+        path: P,
+        timeout: Duration,
+        options: FileOptions,
+    ) -> AsyncLockFuture {
+        Self::lock_timeout_with(path, timeout, options, LockMode::Posix)
+    }
+
+    /// Lock the specified file using the given [`LockBackend`] without blocking the runtime,
+    /// waiting at most `timeout`
+    ///
+    /// See [`AsyncFileLock::lock_timeout`] for details.
+    pub fn lock_timeout_with<P: AsRef<Path>, B: LockBackend + 'static>(
+        path: P,
+        timeout: Duration,
+        options: FileOptions,
+        backend: B,
+    ) -> AsyncLockFuture {
+        AsyncLockFuture::new(Acquisition::timeout(
+            path.as_ref(),
+            timeout,
+            options,
+            backend,
+        ))
+    }
+
+    /// The [`LockBackend`] this lock was taken out with
+    pub fn backend(&self) -> &dyn LockBackend {
+        &*self.state.backend
+    }
+
+    /// How long we had to wait for the lock to become available
+    pub fn waited(&self) -> Duration {
+        self.state.waited
+    }
+
+    /// Whether we currently hold a shared or an exclusive lock
+    pub fn kind(&self) -> LockKind {
+        self.state.kind
+    }
+
+    /// Unlock our locked file and hand it back
+    ///
+    /// The returned future first waits for any writes still in flight to
+    /// land, then releases the lock. Should one of them have failed, the lock
+    /// is released all the same and the error returned.
+    pub fn unlock(self) -> impl Future<Output = Result<File, LockError>> + Send {
+        Flushed::new(self, |file, state, flushed| {
+            let unlocked = flushed
+                .map_err(|err| {
+                    LockError::from_io(Operation::Unlock, err).with_path(state.path.as_deref())
+                })
+                .and(state.release(file.as_raw_fd()));
+
+            match unlocked {
+                Ok(()) => Ok(file),
+                Err(err) => {
+                    close(file, state.access);
+                    Err(err)
+                }
+            }
+        })
+    }
+
+    /// Unlock our locked file and hand it back, ignoring any error
+    ///
+    /// Waits for any writes still in flight to land first, like [`AsyncFileLock::unlock`].
+    pub fn into_inner(self) -> impl Future<Output = File> + Send {
+        Flushed::new(self, |file, state, _| {
+            let _ = state.release(file.as_raw_fd()).is_ok();
+
+            file
+        })
+    }
+
+    fn into_parts(self) -> (File, LockState) {
+        let lock = ManuallyDrop::new(self);
+
+        // `lock` is never dropped, so each field is moved out exactly once
+        unsafe {
+            (
+                ManuallyDrop::into_inner(ptr::read(&lock.file)),
+                ManuallyDrop::into_inner(ptr::read(&lock.state)),
+            )
+        }
+    }
+}
+
+/// Close `file` without releasing the locks other `FileLock`s hold on it
+fn close(file: File, access: Option<Access>) {
+    when_settled(file, move |file| registry::close(file, access));
+}
+
+/// Hand the file behind `file` to `then` once no operation holds on to it any more
+fn when_settled<F: FnOnce(fs::File) + Send + 'static>(file: File, then: F) {
+    let file = match file.try_into_std() {
+        Ok(file) => return then(file),
+        Err(file) => file,
+    };
+
+    // an operation still in flight keeps the file open, which tokio would
+    // then close behind the registry's back, so wait for it to complete
+    thread::spawn(move || {
+        let policy = RetryPolicy::default();
+        let mut file = file;
+        let mut attempts = 0;
+
+        loop {
+            match file.try_into_std() {
+                Ok(file) => return then(file),
+                Err(busy) => file = busy,
+            }
+
+            attempts += 1;
+            thread::sleep(policy.pause(attempts));
+        }
+    });
+}
+
+impl Deref for AsyncFileLock {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl AsyncRead for AsyncFileLock {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().file).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for AsyncFileLock {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.get_mut().file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().file).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.get_mut().file).poll_shutdown(cx)
+    }
+}
+
+impl AsyncSeek for AsyncFileLock {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        Pin::new(&mut *self.get_mut().file).start_seek(position)
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<u64>> {
+        Pin::new(&mut *self.get_mut().file).poll_complete(cx)
+    }
+}
+
+/// Waits for the writes to a lock's file to land, then hands its parts to `then`
+struct Flushed<F> {
+    lock: Option<AsyncFileLock>,
+    then: Option<F>,
+}
+
+impl<T, F: FnOnce(File, LockState, io::Result<()>) -> T> Flushed<F> {
+    fn new(lock: AsyncFileLock, then: F) -> Self {
+        Flushed {
+            lock: Some(lock),
+            then: Some(then),
+        }
+    }
+}
+
+impl<T, F: FnOnce(File, LockState, io::Result<()>) -> T + Unpin> Future for Flushed<F> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+        let this = self.get_mut();
+        let lock = match this.lock {
+            Some(ref mut lock) => lock,
+            None => panic!("Flushed polled after completion"),
+        };
+
+        let flushed = match Pin::new(&mut *lock.file).poll_flush(cx) {
+            Poll::Ready(flushed) => flushed,
+            Poll::Pending => return Poll::Pending,
+        };
+        let (file, state) = this.lock.take().expect("lock is there").into_parts();
+        let then = this.then.take().expect("taken along with the lock");
+
+        Poll::Ready(then(file, state, flushed))
+    }
+}
+
+impl Drop for AsyncFileLock {
+    fn drop(&mut self) {
+        // neither field is used again
+        let (file, state) = unsafe {
+            (
+                ManuallyDrop::take(&mut self.file),
+                ManuallyDrop::take(&mut self.state),
+            )
+        };
+
+        // the lock must outlast any write still in flight
+        when_settled(file, move |file| {
+            let _ = state.release(file.as_raw_fd()).is_ok();
+            registry::close(file, state.access);
+        });
+    }
+}
+
+/// The future returned by [`AsyncFileLock::lock`] and friends
+///
+/// Should it be dropped while the blocking task still waits, the task
+/// stops waiting. If it got the lock anyway, tokio drops the result and
+/// with it the lock.
+#[derive(Debug)]
+pub struct AsyncLockFuture {
+    acquisition: Acquisition,
+    task: Option<JoinHandle<Result<FileLock, LockError>>>,
+}
+
+impl AsyncLockFuture {
+    fn new(acquisition: Acquisition) -> Self {
+        AsyncLockFuture {
+            acquisition,
+            task: None,
+        }
+    }
+}
+
+impl Future for AsyncLockFuture {
+    type Output = Result<AsyncFileLock, LockError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if let Some(acquire) = this.acquisition.start() {
+            this.task = Some(task::spawn_blocking(acquire));
+        }
+
+        let task = match this.task {
+            Some(ref mut task) => task,
+            None => panic!("AsyncLockFuture polled after completion"),
+        };
+
+        let result = match Pin::new(task).poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.task = None;
+
+        Poll::Ready(match result {
+            Ok(Ok(lock)) => {
+                let (file, state) = lock.into_parts();
+
+                Ok(AsyncFileLock {
+                    file: ManuallyDrop::new(File::from_std(file)),
+                    state: ManuallyDrop::new(state),
+                })
+            }
+            Ok(Err(err)) => Err(err),
+            Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+            // the runtime is shutting down
+            Err(_) => Err(this.acquisition.cancelled()),
+        })
+    }
+}
@@ -0,0 +1,165 @@
+use backend::{LockBackend, LockKind};
+use cancel::CancelToken;
+use error::{LockError, Operation};
+use retry::RetryPolicy;
+use std::io::Error;
+use std::os::unix::io::RawFd;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// How long to wait for a lock
+#[derive(Clone, Debug)]
+pub(crate) enum Wait {
+    /// Fail right away if the lock is held elsewhere
+    NonBlocking,
+    /// Wait for as long as it takes, carrying on after being interrupted by a signal
+    Blocking,
+    /// Wait for as long as it takes, unless interrupted by a signal
+    Interruptible,
+    /// Retry as told by `policy` until the lock is ours, or `cancel` has been triggered
+    Poll {
+        policy: RetryPolicy,
+        cancel: Option<CancelToken>,
+    },
+}
+
+impl Wait {
+    pub(crate) fn from_blocking(is_blocking: bool) -> Self {
+        match is_blocking {
+            true => Wait::Blocking,
+            false => Wait::NonBlocking,
+        }
+    }
+
+    pub(crate) fn timeout(timeout: Duration) -> Self {
+        Wait::Poll {
+            policy: RetryPolicy::default().deadline(timeout),
+            cancel: None,
+        }
+    }
+
+    pub(crate) fn cancellable(cancel: &CancelToken) -> Self {
+        Wait::Poll {
+            policy: RetryPolicy::default(),
+            cancel: Some(cancel.clone()),
+        }
+    }
+
+    /// What is left of this after `waited` has gone by, to wait some more
+    pub(crate) fn after(self, waited: Duration) -> Self {
+        match self {
+            Wait::Poll { policy, cancel } => Wait::Poll {
+                policy: match policy.deadline_left(waited) {
+                    Some(left) => policy.deadline(left),
+                    None => policy,
+                },
+                cancel,
+            },
+            wait => wait,
+        }
+    }
+}
+
+/// Whether `err` means the lock is held elsewhere, rather than a real failure
+pub(crate) fn is_contended(err: &Error) -> bool {
+    match err.raw_os_error() {
+        Some(errno) => errno == libc::EAGAIN || errno == libc::EACCES || errno == libc::EWOULDBLOCK,
+        None => false,
+    }
+}
+
+/// Whether `err` means we were interrupted by a signal, and may just try again
+fn is_interrupted(err: &Error) -> bool {
+    err.raw_os_error() == Some(libc::EINTR)
+}
+
+/// Lock `fd` as told by `wait` and return how long that took, and in how many attempts
+///
+/// Timeouts and cancellation are implemented by polling as told by a
+/// [`RetryPolicy`], as none of the locking primitives can be told how long to
+/// block or be woken up reliably.
+pub(crate) fn lock(
+    backend: &dyn LockBackend,
+    fd: RawFd,
+    kind: LockKind,
+    wait: Wait,
+    start: u64,
+    len: u64,
+    operation: Operation,
+) -> Result<(Duration, u32), LockError> {
+    let started = Instant::now();
+
+    let (policy, cancel) = match wait {
+        Wait::NonBlocking => {
+            return match backend.lock(fd, kind, false, start, len) {
+                Ok(()) => Ok((started.elapsed(), 1)),
+                Err(ref err) if is_contended(err) => Err(LockError::WouldBlock {
+                    operation,
+                    path: None,
+                    holder: backend.query(fd, kind, start, len).unwrap_or(None),
+                }),
+                Err(err) => Err(LockError::from_io(operation, err)),
+            };
+        }
+        Wait::Blocking => loop {
+            match backend.lock(fd, kind, true, start, len) {
+                Ok(()) => return Ok((started.elapsed(), 1)),
+                Err(ref err) if is_interrupted(err) => {}
+                Err(err) => return Err(LockError::from_io(operation, err)),
+            }
+        },
+        Wait::Interruptible => {
+            return match backend.lock(fd, kind, true, start, len) {
+                Ok(()) => Ok((started.elapsed(), 1)),
+                Err(err) => Err(LockError::from_io(operation, err)),
+            };
+        }
+        Wait::Poll { policy, cancel } => (policy, cancel),
+    };
+
+    let mut attempts = 0;
+
+    loop {
+        if cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+            return Err(LockError::Cancelled {
+                operation,
+                path: None,
+                waited: started.elapsed(),
+            });
+        }
+
+        attempts += 1;
+        match backend.lock(fd, kind, false, start, len) {
+            Ok(()) => return Ok((started.elapsed(), attempts)),
+            Err(ref err) if is_contended(err) || is_interrupted(err) => {}
+            Err(err) => return Err(LockError::from_io(operation, err)),
+        }
+
+        let waited = started.elapsed();
+        if policy.max_attempts_reached(attempts) {
+            return Err(LockError::RetriesExhausted {
+                operation,
+                path: None,
+                attempts,
+                waited,
+            });
+        }
+
+        let pause = match policy.deadline_left(waited) {
+            Some(left) if left.is_zero() => {
+                return Err(LockError::TimedOut {
+                    operation,
+                    path: None,
+                    waited,
+                });
+            }
+            Some(left) => policy.pause(attempts).min(left),
+            None => policy.pause(attempts),
+        };
+
+        match cancel {
+            Some(ref cancel) => cancel.sleep(pause),
+            None => sleep(pause),
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use backend::LockBackend;
+use cancel::CancelToken;
+use error::{LockError, Operation};
+use file_options::FileOptions;
+use lock_mode::LockMode;
+use lock_state::LockState;
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::unistd::{pipe2, write};
+use registry;
+use retry::RetryPolicy;
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::Instant;
+use wait::Wait;
+use FileLock;
+
+type Outcome = Arc<Mutex<Option<Result<FileLock, LockError>>>>;
+
+/// A lock being waited for in the background, for use with `poll()` based event loops
+///
+/// A single helper thread, shared by all waiters of the process, tries for
+/// the lock on our behalf. The waiter's file descriptor, available through
+/// [`AsRawFd`] and [`AsFd`], becomes readable once that is over, be it with
+/// the lock or with an error. Register it with `epoll`, `mio` or the like
+/// and pick up the outcome with [`LockWaiter::take`] when it fires.
+///
+/// The lock is tried for as with [`RetryPolicy::default`](struct.RetryPolicy.html#impl-Default-for-RetryPolicy):
+/// again after a millisecond at first, backing off to every 50 milliseconds.
+/// So the waiter may only get the lock up to 50 milliseconds after it has
+/// become available, and notices being cancelled just as late.
+///
+/// Dropping the waiter cancels it, and a lock taken out in the meantime is
+/// released again.
+///
+/// # Examples
+///
+///```
+///extern crate file_lock;
+///extern crate nix;
+///
+///use file_lock::{FileOptions, LockWaiter};
+///use nix::poll::{poll, PollFd, PollFlags};
+///use std::os::unix::io::AsRawFd;
+///
+///fn main() {
+///    let options = FileOptions::new().write(true).create(true);
+///    let mut waiter = LockWaiter::lock("myfile.txt", options).unwrap();
+///
+///    let mut fds = [PollFd::new(waiter.as_raw_fd(), PollFlags::POLLIN)];
+///    poll(&mut fds, -1).unwrap();
+///
+///    match waiter.take() {
+///        Some(Ok(_lock)) => println!("Got the lock"),
+///        Some(Err(err)) => panic!("Error getting write lock: {}", err),
+///        None => unreachable!(),
+///    }
+///}
+///```
+#[derive(Debug)]
+pub struct LockWaiter {
+    ready: File,
+    outcome: Outcome,
+    cancel: CancelToken,
+    is_taken: bool,
+}
+
+/// A lock the helper thread tries for
+struct Pending {
+    path: PathBuf,
+    options: FileOptions,
+    file: Option<File>,
+    /// Handed to the lock once we get it
+    backend: Option<Box<dyn LockBackend>>,
+    outcome: Outcome,
+    notify: OwnedFd,
+    cancel: CancelToken,
+    started: Instant,
+    attempts: u32,
+    next: Instant,
+}
+
+/// The locks the helper thread tries for, and whether it is running
+struct Poller {
+    /// The process the thread runs in, as it doesn't survive `fork()`
+    pid: u32,
+    pending: Vec<Pending>,
+    is_running: bool,
+}
+
+static POLLER: Mutex<Option<Poller>> = Mutex::new(None);
+static ADDED: Condvar = Condvar::new();
+
+impl LockWaiter {
+    /// Start waiting for a lock on the specified file
+    ///
+    /// `options` tells how to open the file, and with it whether to take out
+    /// a shared or an exclusive lock, just like with
+    /// [`FileLock::lock`](struct.FileLock.html#method.lock). Fails only if no
+    /// pipe could be set up, anything else is reported by [`LockWaiter::take`].
+    pub fn lock<P: AsRef<Path>>(path: P, options: FileOptions) -> Result<LockWaiter, LockError> {
+        Self::lock_with(path, options, LockMode::Posix)
+    }
+
+    /// Start waiting for a lock on the specified file using the given [`LockBackend`]
+    ///
+    /// See [`LockWaiter::lock`] for details.
+    pub fn lock_with<P: AsRef<Path>, B: LockBackend + 'static>(
+        path: P,
+        options: FileOptions,
+        backend: B,
+    ) -> Result<LockWaiter, LockError> {
+        let path = path.as_ref().to_path_buf();
+        let (ready, notify) = pipe2(OFlag::O_CLOEXEC).map_err(|errno| LockError::Io {
+            operation: Operation::Lock,
+            path: Some(path.clone()),
+            source: errno.into(),
+        })?;
+        let (ready, notify) = unsafe { (File::from_raw_fd(ready), OwnedFd::from_raw_fd(notify)) };
+
+        let outcome = Outcome::default();
+        let cancel = CancelToken::new();
+        let now = Instant::now();
+
+        add(Pending {
+            path,
+            options,
+            file: None,
+            backend: Some(Box::new(backend)),
+            outcome: outcome.clone(),
+            notify,
+            cancel: cancel.clone(),
+            started: now,
+            attempts: 0,
+            next: now,
+        });
+
+        Ok(LockWaiter {
+            ready,
+            outcome,
+            cancel,
+            is_taken: false,
+        })
+    }
+
+    /// Whether waiting is over, so that [`LockWaiter::take`] has an outcome
+    pub fn is_ready(&self) -> bool {
+        self.outcome
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .is_some()
+    }
+
+    /// Stop waiting for the lock
+    ///
+    /// The file descriptor becomes readable shortly after, and
+    /// [`LockWaiter::take`] then returns [`LockError::Cancelled`](enum.LockError.html#variant.Cancelled),
+    /// unless the lock was acquired in the meantime.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Pick up the lock, or why we didn't get it, once waiting is over
+    ///
+    /// Returns `None` while still waiting, and after the outcome has been taken.
+    pub fn take(&mut self) -> Option<Result<FileLock, LockError>> {
+        let outcome = self
+            .outcome
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .take();
+
+        if outcome.is_some() {
+            let _ = self.ready.read(&mut [0]);
+            self.is_taken = true;
+        }
+
+        outcome
+    }
+
+    /// Block until waiting is over and return the outcome
+    ///
+    /// Returns `None` if the outcome has already been taken.
+    pub fn wait(mut self) -> Option<Result<FileLock, LockError>> {
+        // the descriptor becomes readable right after the outcome is there
+        while !self.is_taken && !self.is_ready() {
+            let mut fds = [PollFd::new(self.ready.as_raw_fd(), PollFlags::POLLIN)];
+            let _ = poll(&mut fds, -1);
+        }
+
+        self.take()
+    }
+}
+
+impl AsRawFd for LockWaiter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.ready.as_raw_fd()
+    }
+}
+
+impl AsFd for LockWaiter {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.ready.as_fd()
+    }
+}
+
+impl Drop for LockWaiter {
+    fn drop(&mut self) {
+        // a lock acquired regardless is dropped along with the outcome
+        self.cancel.cancel();
+    }
+}
+
+impl Pending {
+    /// Try for the lock once, returning `self` if we have to try again later
+    fn attempt(mut self, policy: &RetryPolicy) -> Option<Pending> {
+        let outcome = match self.try_lock() {
+            Some(outcome) => outcome,
+            None => {
+                self.next = Instant::now() + policy.pause(self.attempts);
+                return Some(self);
+            }
+        };
+
+        *self.outcome.lock().unwrap_or_else(|err| err.into_inner()) = Some(outcome);
+        let _ = write(self.notify.as_raw_fd(), &[1]);
+
+        None
+    }
+
+    /// The outcome, unless someone else holds the lock
+    fn try_lock(&mut self) -> Option<Result<FileLock, LockError>> {
+        if self.cancel.is_cancelled() {
+            return Some(Err(LockError::Cancelled {
+                operation: Operation::Lock,
+                path: Some(self.path.clone()),
+                waited: self.started.elapsed(),
+            }));
+        }
+
+        let file = match self.file.take() {
+            Some(file) => file,
+            None => match registry::open(&self.path, &self.options) {
+                Ok(file) => file,
+                Err(source) => {
+                    return Some(Err(LockError::Open {
+                        path: self.path.clone(),
+                        source,
+                    }))
+                }
+            },
+        };
+        let backend = self.backend.take().expect("backend is kept until locked");
+        self.attempts += 1;
+
+        let locked = LockState::try_acquire(
+            file.as_raw_fd(),
+            self.options.kind(&file),
+            Wait::NonBlocking,
+            0,
+            0,
+            backend,
+            Some(self.path.clone()),
+        );
+
+        match locked {
+            Ok(mut state) => {
+                state.waited = self.started.elapsed();
+                state.attempts = self.attempts;
+                Some(FileLock::new(file, self.options.access(), Ok(state)))
+            }
+            Err((LockError::WouldBlock { .. }, backend))
+            | Err((LockError::Interrupted { .. }, backend)) => {
+                self.file = Some(file);
+                self.backend = Some(backend);
+                None
+            }
+            Err((err, _)) => Some(FileLock::new(file, self.options.access(), Err(err))),
+        }
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            registry::close(file, self.options.access());
+        }
+    }
+}
+
+fn waiting() -> MutexGuard<'static, Option<Poller>> {
+    let mut poller = POLLER.lock().unwrap_or_else(|err| err.into_inner());
+    let pid = process::id();
+
+    if poller.as_ref().map(|poller| poller.pid) != Some(pid) {
+        // our parent's, and closing their files here could release our own locks
+        mem::forget(poller.take());
+        *poller = Some(Poller {
+            pid,
+            pending: Vec::new(),
+            is_running: false,
+        });
+    }
+    poller
+}
+
+/// Have the helper thread try for `pending`, starting it if need be
+fn add(pending: Pending) {
+    let mut poller = waiting();
+    let poller = poller.as_mut().expect("poller is set up");
+
+    poller.pending.push(pending);
+    if !poller.is_running {
+        poller.is_running = true;
+        thread::spawn(run);
+    }
+    ADDED.notify_one();
+}
+
+/// The helper thread, which tries for every lock when due until none are left
+fn run() {
+    let policy = RetryPolicy::default();
+    let mut guard = waiting();
+
+    loop {
+        let poller = guard.as_mut().expect("poller is set up");
+        let now = Instant::now();
+        let (due, later): (Vec<_>, Vec<_>) = poller
+            .pending
+            .drain(..)
+            .partition(|pending| pending.next <= now);
+        poller.pending = later;
+
+        if due.is_empty() {
+            let next = poller.pending.iter().map(|pending| pending.next).min();
+
+            guard = match next {
+                Some(next) => {
+                    ADDED
+                        .wait_timeout(guard, next - now)
+                        .unwrap_or_else(|err| err.into_inner())
+                        .0
+                }
+                None => {
+                    poller.is_running = false;
+                    return;
+                }
+            };
+            continue;
+        }
+
+        // trying may take a while, so others can add locks in the meantime
+        drop(guard);
+        let again: Vec<_> = due
+            .into_iter()
+            .filter_map(|pending| pending.attempt(&policy))
+            .collect();
+
+        guard = waiting();
+        guard
+            .as_mut()
+            .expect("poller is set up")
+            .pending
+            .extend(again);
+    }
+}